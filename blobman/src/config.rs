@@ -4,15 +4,24 @@
 //! Configuration of the blobman framework.
 
 use app_dirs::{app_dir, app_root, AppDataType};
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
 use std::fs::File;
 use std::path::PathBuf;
+use std::time::Duration;
 use toml;
 
+use digest::DigestData;
 use errors::Result;
+use http::{self, Downloader};
+use http::auth::{AuthSettings, HostAuth};
+use http::proxy::{Proxy, ProxySettings};
+use http::rewrite::UrlRewrites;
+use http::tls::{Identity, TlsSettings};
 use io;
 use notify::NotificationBackend;
 use storage::{filesystem, Storage};
+use storage::tiered::TieredStorage;
 
 
 const DEFAULT_CONFIG: &'static str = r#"[[storage]]
@@ -24,12 +33,176 @@ location = {type = "user_cache", loc = "blobman"}
 #[derive(Debug, Deserialize, Serialize)]
 pub struct UserConfig {
     storage: Vec<StorageInfo>,
+
+    /// Settings for downloading blobs over the network.
+    #[serde(default)]
+    network: NetworkConfig,
+}
+
+/// Settings that control how blobs are downloaded.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct NetworkConfig {
+    /// The maximum number of downloads to run at once.
+    max_concurrent: Option<usize>,
+
+    /// The number of times to retry a download that fails for a reason that
+    /// might be temporary.
+    retries: Option<u32>,
+
+    /// The limit on the time taken to connect to a server, in seconds. Zero
+    /// means no limit.
+    connect_timeout: Option<u64>,
+
+    /// The limit on the time spent waiting for a server to send anything, in
+    /// seconds. Zero means no limit.
+    read_timeout: Option<u64>,
+
+    /// The HTTP proxy to use for all downloads, overriding the `HTTP_PROXY`
+    /// and `HTTPS_PROXY` environment variables. An empty string means that
+    /// no proxy should be used.
+    proxy: Option<String>,
+
+    /// Hosts that should be contacted directly rather than through the
+    /// proxy, overriding the `NO_PROXY` environment variable.
+    no_proxy: Option<Vec<String>>,
+
+    /// Settings for securing TLS connections.
+    #[serde(default)]
+    tls: TlsConfig,
+
+    /// Settings for securing TLS connections to particular hosts. These are
+    /// applied on top of the general ones.
+    #[serde(default)]
+    tls_hosts: BTreeMap<String, TlsConfig>,
+
+    /// Credentials and headers to send to particular hosts. Hosts without
+    /// an entry here use the credentials in the user’s `.netrc` file, if
+    /// any.
+    #[serde(default)]
+    auth: BTreeMap<String, AuthConfig>,
+
+    /// Rules for downloading URLs from somewhere other than where they
+    /// point. The manifest still records the original URLs.
+    #[serde(default)]
+    rewrite: Vec<RewriteConfig>,
+
+    /// Whether to forbid all network access.
+    offline: Option<bool>,
+}
+
+/// A rule that redirects downloads from one set of URLs to another, in the
+/// manner of Git’s `url.<base>.insteadOf` setting.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RewriteConfig {
+    /// The prefix to substitute into matching URLs.
+    url: String,
+
+    /// The URL prefixes that should be replaced.
+    instead_of: Vec<String>,
+}
+
+/// Credentials and headers to send to one host.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AuthConfig {
+    /// A user name for HTTP Basic authentication.
+    username: Option<String>,
+
+    /// The password to go with the user name.
+    password: Option<String>,
+
+    /// The full value of the `Authorization` header, such as
+    /// `"Bearer <token>"`. This takes precedence over a user name and
+    /// password.
+    authorization: Option<String>,
+
+    /// Additional headers to send with every request.
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+}
+
+/// Settings that control how TLS connections are secured.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// Files of additional root certificates to trust.
+    #[serde(default)]
+    ca_certificates: Vec<PathBuf>,
+
+    /// The identity to present to servers that ask for a client certificate.
+    client_identity: Option<IdentityConfig>,
+
+    /// Whether to skip verification of server certificates. For testing
+    /// only!
+    insecure: Option<bool>,
+}
+
+/// Where to find a TLS client identity.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+enum IdentityConfig {
+    /// A PKCS#12 archive.
+    #[serde(rename = "pkcs12")]
+    Pkcs12 {
+        path: PathBuf,
+        #[serde(default)]
+        password: String,
+    },
+
+    /// A PEM certificate file and a PEM private key file.
+    #[serde(rename = "pem")]
+    Pem {
+        certificate: PathBuf,
+        key: PathBuf,
+    },
 }
 
 /// Information about a storage area that blobman can use.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct StorageInfo {
     location: StorageLocation,
+
+    /// The maximum total size of the blobs in this area, in bytes. If
+    /// exceeded, the least recently used blobs are evicted.
+    max_size: Option<u64>,
+
+    /// Digests of blobs that should never be evicted from this area.
+    #[serde(default)]
+    pinned: Vec<DigestData>,
+
+    /// Whether new blobs may be written into this area.
+    #[serde(default)]
+    write: WritePolicy,
+
+    /// Whether blobs found in lower-priority areas should be copied into
+    /// this one when they're used.
+    #[serde(default)]
+    promote: bool,
+
+    /// Whether this area must never be written to.
+    #[serde(default)]
+    readonly: bool,
+}
+
+/// How a storage area participates in the ingestion of new blobs.
+///
+/// Storage areas are consulted in the order that they are listed in the
+/// configuration file. New blobs are written into the first area that
+/// accepts them.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum WritePolicy {
+    /// New blobs may be written into this area.
+    #[serde(rename = "ingest")]
+    Ingest,
+
+    /// New blobs are never written into this area. It can still receive
+    /// copies of existing blobs if promotion is enabled.
+    #[serde(rename = "never")]
+    Never,
+}
+
+impl Default for WritePolicy {
+    fn default() -> Self {
+        WritePolicy::Ingest
+    }
 }
 
 /// A location where blobs can be stored.
@@ -55,18 +228,85 @@ impl StorageInfo {
     /// Because the StorageInfo multiplexes over different backend
     /// implementations, we return a trait object.
     pub fn open(&self) -> Result<Box<Storage>> {
-        match self.location {
+        let mut storage = match self.location {
             StorageLocation::Filesystem(ref prefix) => {
                 if !prefix.is_absolute() {
                     return err_msg!("the path associated with filesystem storage must be absolute; got {}", prefix.display());
                 }
-                Ok(Box::new(filesystem::FilesystemStorage::new(prefix)))
+                filesystem::FilesystemStorage::new(prefix)
             },
             StorageLocation::UserCache(ref subdir) => {
                 let d = app_dir(AppDataType::UserCache, &::APP_INFO, subdir)?;
-                Ok(Box::new(filesystem::FilesystemStorage::new(&d)))
+                filesystem::FilesystemStorage::new(&d)
             },
+        };
+
+        storage.set_max_size(self.max_size);
+        storage.set_readonly(self.readonly);
+
+        for digest in &self.pinned {
+            storage.pin_transiently(digest);
         }
+
+        Ok(Box::new(storage))
+    }
+
+    /// Determine whether new blobs may be written into this storage area.
+    pub fn accepts_ingest(&self) -> bool {
+        self.write == WritePolicy::Ingest && !self.readonly
+    }
+
+    /// Determine whether blobs found in lower-priority storage areas should
+    /// be copied into this one.
+    pub fn accepts_promotion(&self) -> bool {
+        self.promote && !self.readonly
+    }
+}
+
+
+impl TlsConfig {
+    /// Apply these settings on top of *settings*.
+    fn apply(&self, settings: &mut TlsSettings) {
+        for path in &self.ca_certificates {
+            settings.add_root_certificates(path);
+        }
+
+        if let Some(ref id) = self.client_identity {
+            settings.set_identity(Some(match *id {
+                IdentityConfig::Pkcs12 { ref path, ref password } => Identity::Pkcs12 {
+                    path: path.clone(),
+                    password: password.clone(),
+                },
+                IdentityConfig::Pem { ref certificate, ref key } => Identity::Pem {
+                    certificate: certificate.clone(),
+                    key: key.clone(),
+                },
+            }));
+        }
+
+        if let Some(insecure) = self.insecure {
+            settings.set_insecure(insecure);
+        }
+    }
+}
+
+
+impl AuthConfig {
+    /// Convert these settings into the form used by the downloader.
+    fn to_host_auth(&self) -> HostAuth {
+        let mut auth = HostAuth::new();
+
+        if let Some(ref value) = self.authorization {
+            auth.set_authorization(value);
+        } else if let Some(ref user) = self.username {
+            auth.set_basic(user, self.password.as_ref().map(|p| p.as_ref()));
+        }
+
+        for (name, value) in &self.headers {
+            auth.add_header(name, value);
+        }
+
+        auth
     }
 }
 
@@ -96,6 +336,9 @@ impl UserConfig {
 
     /// Get a storage backend.
     ///
+    /// All of the configured storage areas are combined into a single
+    /// TieredStorage, in the order in which they are listed.
+    ///
     /// This is a bit of a hack; the main logic should probably be confined to
     /// the Session type.
     pub fn get_storage<B: NotificationBackend>(&self, nbe: &mut B) -> Result<Box<Storage>> {
@@ -103,10 +346,155 @@ impl UserConfig {
             return err_msg!("no storage backends defined in the config file");
         }
 
-        if self.storage.len() > 1 {
-            bm_warning!(nbe, "I only pay attention to the first storage area that's been configured");
+        if !self.storage.iter().any(|s| s.accepts_ingest()) {
+            bm_warning!(nbe, "none of the configured storage areas accept new blobs");
+        }
+
+        let mut tiered = TieredStorage::new();
+
+        for info in &self.storage {
+            tiered.add_tier(info.open()?, info.accepts_ingest(), info.accepts_promotion());
+        }
+
+        Ok(Box::new(tiered))
+    }
+
+    /// Override the configured maximum number of simultaneous downloads.
+    pub fn set_max_concurrent_downloads(&mut self, n: usize) {
+        self.network.max_concurrent = Some(n);
+    }
+
+    /// Override the configured number of times to retry failed downloads.
+    pub fn set_download_retries(&mut self, n: u32) {
+        self.network.retries = Some(n);
+    }
+
+    /// Override the configured connection timeout, in seconds.
+    ///
+    /// Zero means no limit.
+    pub fn set_connect_timeout(&mut self, secs: u64) {
+        self.network.connect_timeout = Some(secs);
+    }
+
+    /// Override the configured read timeout, in seconds.
+    ///
+    /// Zero means no limit.
+    pub fn set_read_timeout(&mut self, secs: u64) {
+        self.network.read_timeout = Some(secs);
+    }
+
+    /// Override whether the configuration forbids network access.
+    ///
+    /// Offline mode is still turned on if the environment asks for it.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.network.offline = Some(offline);
+    }
+
+    /// Determine whether network access is forbidden.
+    ///
+    /// Offline mode is on if either the configuration or the environment
+    /// asks for it.
+    pub fn is_offline(&self) -> bool {
+        self.network.offline.unwrap_or(false) || http::offline_from_env()
+    }
+
+    /// Create an HTTP(S) downloader set up according to the configuration.
+    pub fn get_downloader<B: NotificationBackend>(&self, nbe: &mut B) -> Result<Downloader> {
+        let mut downloader = Downloader::new()?;
+        downloader.set_offline(self.is_offline());
+
+        if let Some(n) = self.network.max_concurrent {
+            downloader.set_max_concurrent(n);
+        }
+
+        if let Some(n) = self.network.retries {
+            downloader.set_retries(n);
         }
 
-        self.storage[0].open()
+        if let Some(secs) = self.network.connect_timeout {
+            downloader.set_connect_timeout(timeout_from_secs(secs));
+        }
+
+        if let Some(secs) = self.network.read_timeout {
+            downloader.set_read_timeout(timeout_from_secs(secs));
+        }
+
+        if self.network.proxy.is_some() || self.network.no_proxy.is_some() {
+            let mut proxies = ProxySettings::from_env()?;
+
+            if let Some(ref spec) = self.network.proxy {
+                let proxy = if spec.len() == 0 {
+                    None
+                } else {
+                    Some(ctry!(Proxy::parse(spec); "invalid proxy setting in the configuration file"))
+                };
+
+                proxies.set_http_proxy(proxy.clone());
+                proxies.set_https_proxy(proxy);
+            }
+
+            if let Some(ref hosts) = self.network.no_proxy {
+                proxies.set_no_proxy(hosts.clone());
+            }
+
+            downloader.set_proxies(proxies);
+        }
+
+        if self.network.auth.len() > 0 {
+            let mut auth = AuthSettings::from_netrc()?;
+
+            for (host, host_config) in &self.network.auth {
+                auth.set_host_auth(host, host_config.to_host_auth());
+            }
+
+            downloader.set_auth(auth);
+        }
+
+        if self.network.rewrite.len() > 0 {
+            let mut rewrites = UrlRewrites::new();
+
+            for rule in &self.network.rewrite {
+                for prefix in &rule.instead_of {
+                    rewrites.add_rule(prefix, &rule.url);
+                }
+            }
+
+            downloader.set_rewrites(rewrites);
+        }
+
+        let mut tls = TlsSettings::new();
+        self.network.tls.apply(&mut tls);
+
+        if tls != TlsSettings::new() {
+            if tls.is_insecure() {
+                bm_warning!(nbe, "TLS certificate verification is disabled; downloads are not secure");
+            }
+
+            ctry!(downloader.set_tls_settings(&tls); "couldn\'t set up TLS");
+        }
+
+        for (host, host_config) in &self.network.tls_hosts {
+            let mut host_tls = tls.clone();
+            host_config.apply(&mut host_tls);
+
+            if host_tls.is_insecure() && !tls.is_insecure() {
+                bm_warning!(nbe, "TLS certificate verification is disabled for {}; downloads from it are not secure",
+                            host);
+            }
+
+            ctry!(downloader.set_host_tls_settings(host, &host_tls); "couldn\'t set up TLS for {}", host);
+        }
+
+        Ok(downloader)
+    }
+}
+
+
+/// Convert a timeout setting to a Duration, where zero means no limit.
+fn timeout_from_secs(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
     }
 }
@@ -63,7 +63,7 @@ pub fn create() -> DigestComputer {
 
 
 /// A vector of bytes holding a cryptographic digest.
-#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+#[derive(Copy,Clone,Debug,Eq,Hash,Ord,PartialEq,PartialOrd)]
 pub struct DigestData([u8; N_BYTES]);
 
 impl DigestData {
@@ -80,16 +80,24 @@ impl DigestData {
         Self::from(dc)
     }
 
-    /// Given a base path, create a child path from this digest's value. The
+    /// Given a base path, compute a child path from this digest's value. The
     /// child path has a subdirectory from the hex value of the first byte of
-    /// the digest, then a name consisting of the rest of the hex data. **The
-    /// first-byte subdirectory and all parent directories are created when
-    /// you call this function!**
-    pub fn create_two_part_path(&self, base: &Path) -> Result<PathBuf> {
+    /// the digest, then a name consisting of the rest of the hex data. The
+    /// filesystem is not touched.
+    pub fn two_part_path(&self, base: &Path) -> PathBuf {
         let mut p = base.to_path_buf();
         p.push(format!("{:02x}", self.0[0]));
-        fs::create_dir_all(&p)?;
         p.push(bytes_to_hex(&self.0[1..]));
+        p
+    }
+
+    /// Like `two_part_path()`, but **the first-byte subdirectory and all
+    /// parent directories are created when you call this function!**
+    pub fn create_two_part_path(&self, base: &Path) -> Result<PathBuf> {
+        let p = self.two_part_path(base);
+        if let Some(parent) = p.parent() {
+            fs::create_dir_all(parent)?;
+        }
         Ok(p)
     }
 
@@ -174,6 +182,45 @@ impl<W: io::Write> Shim<W> {
     pub fn finish(self) -> (W, DigestData) {
         (self.inner, self.computer.into())
     }
+
+    /// Get a reference to the wrapped writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the wrapped writer.
+    ///
+    /// Data written directly to the writer are not included in the digest.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Include data in the digest without passing them on to the wrapped
+    /// writer.
+    ///
+    /// This is useful when the writer already holds the beginning of the
+    /// stream, as happens when an interrupted download is resumed. The
+    /// number of bytes processed is returned.
+    pub fn preload<R: io::Read>(&mut self, reader: &mut R) -> IoResult<u64> {
+        let mut buf = [0u8; 8192];
+        let mut n_bytes = 0;
+
+        loop {
+            let n = reader.read(&mut buf)?;
+
+            if n == 0 {
+                return Ok(n_bytes);
+            }
+
+            self.computer.input(&buf[..n]);
+            n_bytes += n as u64;
+        }
+    }
+
+    /// Forget about all of the data processed so far.
+    pub fn reset(&mut self) {
+        self.computer = create();
+    }
 }
 
 impl<W: io::Write> io::Write for Shim<W> {
@@ -18,6 +18,7 @@ use hyper;
 use native_tls;
 use std::{convert, io, num};
 use toml;
+use toml_edit;
 
 
 error_chain! {
@@ -34,6 +35,7 @@ error_chain! {
         ParseInt(num::ParseIntError) #[doc = "An error related to parsing integers."];
         TomlDe(toml::de::Error) #[doc = "A deserialization error from the [toml](https://docs.rs/toml) crate."];
         TomlSer(toml::ser::Error) #[doc = "A serialization error from the [toml](https://docs.rs/toml) crate."];
+        TomlEdit(toml_edit::TomlError) #[doc = "A parse error from the [toml_edit](https://docs.rs/toml_edit) crate."];
     }
 
     errors {
@@ -42,6 +44,12 @@ error_chain! {
             description("the item is not the expected length")
             display("expected length {}; found {}", expected, observed)
         }
+
+        /// A download was needed, but network access has been disabled.
+        Offline(what: String) {
+            description("network access is disabled in offline mode")
+            display("cannot download {}: network access is disabled in offline mode", what)
+        }
     }
 }
 
@@ -0,0 +1,113 @@
+// Copyright 2017 Peter Williams and collaborators
+// Licensed under the MIT License.
+
+/*!
+Fetching blobs from `file://` URLs.
+
+These are handy when data are staged on a shared filesystem. Only local
+paths are supported: the host part of the URL must be empty or `localhost`.
+
+*/
+
+use std::fs::File;
+use std::path::PathBuf;
+
+use errors::Result;
+use super::percent_decode;
+
+
+/// The size of the buffer used when copying files. This is much larger than
+/// the default since the data are probably not going anywhere else.
+pub const COPY_BUFFER_SIZE: usize = 1 << 20;
+
+
+/// Determine whether *url* is a `file://` URL.
+pub fn is_file_url(url: &str) -> bool {
+    // Compare bytes, since the seventh byte might fall in the middle of a
+    // character.
+    url.as_bytes().get(..7).map_or(false, |p| p.eq_ignore_ascii_case(b"file://"))
+}
+
+
+/// Get the local path named by a `file://` URL.
+pub fn path_from_url(url: &str) -> Result<PathBuf> {
+    if !is_file_url(url) {
+        return err_msg!("\"{}\" is not a file:// URL", url);
+    }
+
+    let rest = &url[7..];
+
+    let path = match rest.find('/') {
+        Some(0) => rest,
+        Some(i) if rest[..i].eq_ignore_ascii_case("localhost") => &rest[i..],
+        _ => { return err_msg!("cannot fetch \"{}\": only local file:// URLs are supported", url); },
+    };
+
+    let path = percent_decode(path.split(|c| c == '?' || c == '#').next().unwrap())?;
+
+    // "file:///C:/data" names "C:/data" on Windows.
+    if cfg!(windows) && path.len() > 2 && path.as_bytes()[2] == b':' {
+        return Ok(PathBuf::from(&path[1..]));
+    }
+
+    Ok(PathBuf::from(path))
+}
+
+
+/// Open the file named by a `file://` URL.
+pub fn open(url: &str) -> Result<File> {
+    let path = path_from_url(url)?;
+    Ok(ctry!(File::open(&path); "couldn\'t open {}", path.display()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_url_detection() {
+        assert!(is_file_url("file:///tmp/blob"));
+        assert!(is_file_url("FILE:///tmp/blob"));
+        assert!(!is_file_url("https://example.com/blob"));
+        assert!(!is_file_url("file:"));
+        assert!(!is_file_url(""));
+    }
+
+    #[test]
+    fn file_url_detection_non_ascii() {
+        assert!(!is_file_url("abcdefé"));
+        assert!(!is_file_url("fileé://x"));
+        assert!(is_file_url("file:///tmp/é"));
+        assert!(path_from_url("abcdefé").is_err());
+    }
+
+    #[test]
+    fn local_paths() {
+        assert_eq!(path_from_url("file:///tmp/blob").unwrap(), PathBuf::from("/tmp/blob"));
+        assert_eq!(path_from_url("file://localhost/tmp/blob").unwrap(), PathBuf::from("/tmp/blob"));
+        assert_eq!(path_from_url("file://LocalHost/tmp/blob").unwrap(), PathBuf::from("/tmp/blob"));
+        assert_eq!(path_from_url("file:///tmp/blob?x=1#frag").unwrap(), PathBuf::from("/tmp/blob"));
+    }
+
+    #[test]
+    fn percent_decoded_paths() {
+        assert_eq!(path_from_url("file:///tmp/my%20blob%23.bin").unwrap(), PathBuf::from("/tmp/my blob#.bin"));
+        assert_eq!(path_from_url("file:///tmp/%C3%A9t%C3%A9").unwrap(), PathBuf::from("/tmp/été"));
+    }
+
+    #[test]
+    fn remote_hosts_rejected() {
+        assert!(path_from_url("file://server/share/blob").is_err());
+        assert!(path_from_url("file://localhost.example.com/blob").is_err());
+        assert!(path_from_url("file://server").is_err());
+    }
+
+    #[test]
+    fn invalid_escapes_rejected() {
+        assert!(path_from_url("file:///tmp/100%").is_err());
+        assert!(path_from_url("file:///tmp/%zz").is_err());
+        assert!(path_from_url("file:///tmp/%+1").is_err());
+        assert!(path_from_url("file:///tmp/%FF").is_err());
+    }
+}
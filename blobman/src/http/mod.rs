@@ -0,0 +1,1463 @@
+// Copyright 2017 Peter Williams and collaborators
+// Licensed under the MIT License.
+
+/*!
+Retrieving blobs over HTTP(S), or from `file://` URLs.
+
+This code is very directly derived from the `examples/hyper-client.rs` file
+provided in the `tokio-tls` Git repository.
+
+*/
+
+use bytes::buf::{Buf, BufMut};
+use futures::{task, Async, Poll};
+use futures::future::{self, err, Future, Loop};
+use futures::stream::{self, Stream};
+use hyper::{Body, Client, Request, Method, Response, StatusCode, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::{ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, ETag, EntityTag, IfRange,
+                    Location, Range};
+use native_tls::TlsConnector;
+use std::cell::RefCell;
+use std::cmp;
+use std::env;
+use std::fs::File;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::result::Result as StdResult;
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Decoder, Encoder, Framed, FramedParts};
+use tokio_service::Service;
+use tokio_tls::{TlsConnectorExt, TlsStream};
+
+use errors::{Error, ErrorKind, Result};
+use notify::NotificationBackend;
+use self::auth::AuthSettings;
+use self::proxy::ProxySettings;
+use self::rewrite::UrlRewrites;
+use self::tls::TlsSettings;
+
+
+pub mod auth;
+pub mod file;
+pub mod proxy;
+pub mod rewrite;
+pub mod tls;
+
+
+#[derive(Debug)]
+enum MaybeTls<T> {
+    Yes(TlsStream<T>),
+    No(T),
+}
+
+impl<T: Read + Write> Read for MaybeTls<T> {
+    // TODO: implement rest of functions in case sub-types have specialized
+    // implementations
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            MaybeTls::Yes(ref mut s) => s.read(buf),
+            MaybeTls::No(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl<T: Read + Write> Write for MaybeTls<T> {
+    // TODO: implement rest of functions in case sub-types have specialized
+    // implementations
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            MaybeTls::Yes(ref mut s) => s.write(buf),
+            MaybeTls::No(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            MaybeTls::Yes(ref mut s) => s.flush(),
+            MaybeTls::No(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> AsyncRead for MaybeTls<T> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        match *self {
+            MaybeTls::Yes(ref s) => s.prepare_uninitialized_buffer(buf),
+            MaybeTls::No(ref s) => s.prepare_uninitialized_buffer(buf),
+        }
+    }
+
+    fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> Poll<usize, io::Error> where Self: Sized {
+        match *self {
+            MaybeTls::Yes(ref mut s) => s.read_buf(buf),
+            MaybeTls::No(ref mut s) => s.read_buf(buf),
+        }
+    }
+
+    fn framed<C: Encoder + Decoder>(self, codec: C) -> Framed<Self, C> where Self: AsyncWrite + Sized {
+        match self {
+            MaybeTls::Yes(s) => {
+                let (parts, codec) = s.framed(codec).into_parts_and_codec();
+                Framed::from_parts(FramedParts {
+                    inner: MaybeTls::Yes(parts.inner),
+                    readbuf: parts.readbuf,
+                    writebuf: parts.writebuf,
+                }, codec)
+            },
+            MaybeTls::No(s) => {
+                let (parts, codec) = s.framed(codec).into_parts_and_codec();
+                Framed::from_parts(FramedParts {
+                    inner: MaybeTls::No(parts.inner),
+                    readbuf: parts.readbuf,
+                    writebuf: parts.writebuf,
+                }, codec)
+            },
+        }
+    }
+
+    //fn split(self) -> (ReadHalf<Self>, WriteHalf<Self>) where Self: AsyncWrite + Sized {}
+}
+
+impl<T: AsyncWrite + AsyncRead> AsyncWrite for MaybeTls<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            MaybeTls::Yes(ref mut s) => s.shutdown(),
+            MaybeTls::No(ref mut s) => s.shutdown(),
+        }
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> where Self: Sized {
+        match *self {
+            MaybeTls::Yes(ref mut s) => s.write_buf(buf),
+            MaybeTls::No(ref mut s) => s.write_buf(buf),
+        }
+    }
+}
+
+
+#[derive(Clone)]
+struct HttpsConnector {
+    tls: Arc<TlsConnector>,
+    host_tls: Arc<HashMap<String, Arc<TlsConnector>>>,
+    http: HttpConnector,
+    handle: Handle,
+    connect_timeout: Option<Duration>,
+    proxies: Arc<ProxySettings>,
+}
+
+impl HttpsConnector {
+    /// Open a connection to the host named in *uri*, without any time limit.
+    fn connect(&self, uri: Uri) -> Box<Future<Item = MaybeTls<TcpStream>, Error = io::Error>> {
+        let proxy = self.proxies.proxy_for(&uri).cloned();
+
+        // The simple case. If we're going through a proxy, we just connect to
+        // it, and the request is sent in a form that tells it where to go.
+        if uri.scheme() == Some("http") {
+            let target = match proxy {
+                Some(p) => match p.uri() {
+                    Ok(u) => u,
+                    Err(e) => { return Box::new(err(e.into())); },
+                },
+                None => uri,
+            };
+
+            return Box::new(self.http.call(target).map(|tcp| MaybeTls::No(tcp)));
+        }
+
+        // We only support one other option at the moment ...
+        if uri.scheme() != Some("https") {
+            return Box::new(err(io::Error::new(io::ErrorKind::Other,
+                                               "only HTTP and HTTPS are supported")))
+        }
+
+        // Look up the host that we're connecting to as we're going to validate
+        // this as part of the TLS handshake.
+        let host = match uri.host() {
+            Some(s) => s.to_string(),
+            None =>  {
+                return Box::new(err(io::Error::new(io::ErrorKind::Other,
+                                                   "missing host")))
+            }
+        };
+
+        // Delegate to the standard `HttpConnector` type to create a connected
+        // TCP socket, going through a tunnel if we're using a proxy.
+        let tcp = match proxy {
+            Some(p) => {
+                let proxy_uri = match p.uri() {
+                    Ok(u) => u,
+                    Err(e) => { return Box::new(err(e.into())); },
+                };
+                let tunnel_host = host.clone();
+                let port = uri.port().unwrap_or(443);
+
+                Box::new(self.http.call(proxy_uri).and_then(move |tcp| {
+                    proxy::tunnel(tcp, tunnel_host, port, &p)
+                })) as Box<Future<Item = TcpStream, Error = io::Error>>
+            },
+            None => Box::new(self.http.call(uri)),
+        };
+
+        // Once we've got that socket initiate the TLS handshake with the host
+        // name that's provided in the URI we extracted above, using any
+        // settings specific to that host.
+        let tls_cx = self.host_tls.get(&host.to_lowercase()).unwrap_or(&self.tls).clone();
+        Box::new(tcp.and_then(move |tcp| {
+            tls_cx
+                .connect_async(&host, tcp)
+                .map(|tls| MaybeTls::Yes(tls))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        }))
+    }
+}
+
+impl Service for HttpsConnector {
+    type Request = Uri;
+    type Response = MaybeTls<TcpStream>;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let connecting = self.connect(uri);
+
+        let limit = match self.connect_timeout {
+            Some(l) => l,
+            None => { return connecting; },
+        };
+
+        let expired = match Timeout::new(limit, &self.handle) {
+            Ok(t) => t.then(move |_| Err(io::Error::new(io::ErrorKind::TimedOut,
+                                                        format!("connection timed out after {} seconds",
+                                                                limit.as_secs())))),
+            Err(e) => { return Box::new(err(e)); },
+        };
+
+        Box::new(connecting.select(expired).map(|(conn, _)| conn).map_err(|(e, _)| e))
+    }
+}
+
+
+/// The maximum number of redirections that we will follow for one download.
+const MAX_REDIRECTS: usize = 16;
+
+/// The number of downloads that a Downloader will run at once unless told
+/// otherwise.
+pub const DEFAULT_MAX_CONCURRENT: usize = 4;
+
+/// The default number of times that a failed transfer is retried.
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// The default limit on the time taken to open a connection, in seconds.
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+
+/// The default limit on the time spent waiting for a server to send us
+/// anything, in seconds.
+pub const DEFAULT_READ_TIMEOUT: u64 = 60;
+
+/// The environment variable that puts blobman into offline mode. Any value
+/// other than an empty string or `0` turns it on.
+pub const OFFLINE_ENV_VAR: &'static str = "BLOBMAN_OFFLINE";
+
+/// The delay before the first retry of a failed transfer, in seconds. The
+/// delay doubles with every subsequent retry.
+const INITIAL_BACKOFF: u64 = 1;
+
+/// The longest that we will wait between retries, in seconds.
+const MAX_BACKOFF: u64 = 60;
+
+type HttpsClient = Client<HttpsConnector>;
+
+
+/// The things that a transfer needs to know about the Downloader running it.
+#[derive(Clone)]
+struct Context {
+    client: HttpsClient,
+    handle: Handle,
+    auth: Arc<AuthSettings>,
+    rewrites: Arc<UrlRewrites>,
+    offline: bool,
+    progress: Reporter,
+    proxies: Arc<ProxySettings>,
+    read_timeout: Option<Duration>,
+    retries: u32,
+}
+
+
+/// An event in the progress of a transfer, waiting to be passed along to
+/// the notification backend.
+enum ProgressEvent {
+    Start(usize, String, u64, Option<u64>),
+    Update(usize, u64),
+    End(usize),
+}
+
+impl ProgressEvent {
+    fn send<B: NotificationBackend>(self, nbe: &mut B) {
+        match self {
+            ProgressEvent::Start(id, what, n_bytes, total) => nbe.progress_start(id, &what, n_bytes, total),
+            ProgressEvent::Update(id, n_bytes) => nbe.progress_update(id, n_bytes),
+            ProgressEvent::End(id) => nbe.progress_end(id),
+        }
+    }
+}
+
+
+/// Reports on the progress of a transfer.
+///
+/// The transfers can't hold on to the notification backend while they're
+/// running, so the events are queued up and passed along whenever the
+/// event loop wakes us up; see `run()`.
+#[derive(Clone)]
+struct Reporter {
+    id: usize,
+    what: String,
+    events: Rc<RefCell<Vec<ProgressEvent>>>,
+}
+
+impl Reporter {
+    fn start(&self, n_bytes: u64, total: Option<u64>) {
+        self.events.borrow_mut().push(ProgressEvent::Start(self.id, self.what.clone(), n_bytes, total));
+    }
+
+    fn update(&self, n_bytes: u64) {
+        let mut events = self.events.borrow_mut();
+
+        // There's no point in sending stale byte counts.
+        if let Some(&mut ProgressEvent::Update(id, ref mut n)) = events.last_mut() {
+            if id == self.id {
+                *n = n_bytes;
+                return;
+            }
+        }
+
+        events.push(ProgressEvent::Update(self.id, n_bytes));
+    }
+
+    fn end(&self) {
+        self.events.borrow_mut().push(ProgressEvent::End(self.id));
+    }
+}
+
+
+/// A reusable HTTP(S) client that can run many downloads concurrently.
+///
+/// Setting up the event loop and the TLS machinery isn't free, and the
+/// client keeps connections alive between requests, so when many blobs need
+/// to be fetched it is best to create one Downloader and use it for all of
+/// them.
+///
+/// Transfers that fail for reasons that might be temporary — dropped
+/// connections, timeouts, server errors, and rate limiting — are retried
+/// with exponential backoff.
+pub struct Downloader {
+    core: Core,
+    connector: HttpsConnector,
+    cx: Context,
+    max_concurrent: usize,
+}
+
+
+impl Downloader {
+    /// Create and return a new Downloader.
+    ///
+    /// Proxies are configured from the environment, as described in
+    /// `ProxySettings::from_env()`, and credentials are taken from the
+    /// user’s `.netrc` file. Offline mode is turned on if the environment
+    /// asks for it; see `offline_from_env()`.
+    pub fn new() -> Result<Self> {
+        let core = Core::new()?;
+        let handle = core.handle();
+        let proxies = Arc::new(ProxySettings::from_env()?);
+
+        // Create a custom "connector" for Hyper which will route connections
+        // through the `TlsConnector` we create here after routing them
+        // through `HttpConnector` first.
+        let mut connector = HttpsConnector {
+            tls: Arc::new(TlsConnector::builder()?.build()?),
+            host_tls: Arc::new(HashMap::new()),
+            http: HttpConnector::new(2, &handle),
+            handle: handle.clone(),
+            connect_timeout: Some(Duration::from_secs(DEFAULT_CONNECT_TIMEOUT)),
+            proxies: proxies.clone(),
+        };
+        connector.http.enforce_http(false);
+
+        Ok(Downloader {
+            cx: Context {
+                client: make_client(&connector),
+                handle: handle,
+                auth: Arc::new(AuthSettings::from_netrc()?),
+                rewrites: Arc::new(UrlRewrites::new()),
+                offline: offline_from_env(),
+                progress: Reporter {
+                    id: 0,
+                    what: String::new(),
+                    events: Rc::new(RefCell::new(Vec::new())),
+                },
+                proxies: proxies,
+                read_timeout: Some(Duration::from_secs(DEFAULT_READ_TIMEOUT)),
+                retries: DEFAULT_RETRIES,
+            },
+            core: core,
+            connector: connector,
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+        })
+    }
+
+    /// Set the maximum number of transfers that `download_many()` will run
+    /// at once.
+    ///
+    /// Values smaller than one are treated as one.
+    pub fn set_max_concurrent(&mut self, n: usize) -> &mut Self {
+        self.max_concurrent = cmp::max(n, 1);
+        self
+    }
+
+    /// Set the number of times that a transfer is retried after failing
+    /// for a reason that might be temporary.
+    pub fn set_retries(&mut self, retries: u32) -> &mut Self {
+        self.cx.retries = retries;
+        self
+    }
+
+    /// Set the limit on the time taken to open a connection to a server.
+    ///
+    /// `None` means that there is no limit.
+    pub fn set_connect_timeout(&mut self, limit: Option<Duration>) -> &mut Self {
+        self.connector.connect_timeout = limit;
+        self.cx.client = make_client(&self.connector);
+        self
+    }
+
+    /// Set how TLS connections are secured, for hosts that don't have
+    /// settings of their own.
+    pub fn set_tls_settings(&mut self, settings: &TlsSettings) -> Result<&mut Self> {
+        self.connector.tls = Arc::new(settings.build_connector()?);
+        self.cx.client = make_client(&self.connector);
+        Ok(self)
+    }
+
+    /// Set how TLS connections to the host named *host* are secured.
+    ///
+    /// These settings replace the general ones entirely for this host, so
+    /// they should be derived from them if that's what's wanted.
+    pub fn set_host_tls_settings(&mut self, host: &str, settings: &TlsSettings) -> Result<&mut Self> {
+        let connector = Arc::new(settings.build_connector()?);
+        Arc::make_mut(&mut self.connector.host_tls).insert(host.to_lowercase(), connector);
+        self.cx.client = make_client(&self.connector);
+        Ok(self)
+    }
+
+    /// Set how requests are authenticated.
+    pub fn set_auth(&mut self, auth: AuthSettings) -> &mut Self {
+        self.cx.auth = Arc::new(auth);
+        self
+    }
+
+    /// Set whether network access is forbidden.
+    ///
+    /// In offline mode, every download that isn't from a local file fails
+    /// immediately with an error of kind `ErrorKind::Offline`.
+    pub fn set_offline(&mut self, offline: bool) -> &mut Self {
+        self.cx.offline = offline;
+        self
+    }
+
+    /// Set the rules used to rewrite URLs before they are downloaded.
+    pub fn set_rewrites(&mut self, rewrites: UrlRewrites) -> &mut Self {
+        self.cx.rewrites = Arc::new(rewrites);
+        self
+    }
+
+    /// Determine whether downloading *uri* is allowed at all.
+    ///
+    /// Everything is allowed unless we're in offline mode, in which case
+    /// only URLs that point to local files once the rewrite rules have been
+    /// applied are allowed.
+    pub fn is_allowed(&self, uri: &str) -> bool {
+        !self.cx.offline || file::is_file_url(&self.cx.rewrites.rewrite(uri))
+    }
+
+    /// Set which connections should go through proxy servers.
+    pub fn set_proxies(&mut self, proxies: ProxySettings) -> &mut Self {
+        let proxies = Arc::new(proxies);
+        self.connector.proxies = proxies.clone();
+        self.cx.proxies = proxies;
+        self.cx.client = make_client(&self.connector);
+        self
+    }
+
+    /// Set the limit on the time spent waiting for a server to send a
+    /// response or the next piece of data.
+    ///
+    /// `None` means that there is no limit.
+    pub fn set_read_timeout(&mut self, limit: Option<Duration>) -> &mut Self {
+        self.cx.read_timeout = limit;
+        self
+    }
+
+    /// Download over HTTP or HTTPS into a Write object.
+    ///
+    /// Because our HTTP layer is fancy and asynchronous while the rest of
+    /// our operation is synchronous, we can't just return a simple Read
+    /// stream. The number of bytes written is returned.
+    ///
+    /// Since the data written into *dest* can't be taken back, a transfer
+    /// is only retried if it fails before any data have been received.
+    /// Progress is reported to *nbe*.
+    pub fn download<W: Write, B: NotificationBackend>(&mut self, uri: &str, dest: W, nbe: &mut B) -> Result<u64> {
+        let transfer = with_retries(&self.cx, 0, uri, dest, fetch);
+        let (_dest, n_bytes) = run(&mut self.core, &self.cx.progress, transfer, nbe)?;
+        Ok(n_bytes)
+    }
+
+    /// Download into a destination that may be able to take the contents of
+    /// local files without copying them.
+    ///
+    /// This works like `download()`, except that if *uri* is a `file://`
+    /// URL, *dest* is given the chance to clone the file, as filesystems
+    /// that support reflinks can. Nothing is resumed, so *dest* should be
+    /// empty.
+    pub fn download_cloning<D: ResumableDest, B: NotificationBackend>(&mut self, uri: &str, dest: D, nbe: &mut B)
+                                                                     -> Result<u64>
+    {
+        let transfer = with_retries(&self.cx, 0, uri, dest, fetch_cloning);
+        let (_dest, n_bytes) = run(&mut self.core, &self.cx.progress, transfer, nbe)?;
+        Ok(n_bytes)
+    }
+
+    /// Run a batch of downloads concurrently.
+    ///
+    /// Each job is a URL and the Write object into which its data should be
+    /// streamed. At most `max_concurrent` transfers are in flight at any
+    /// one time. The returned vector has one entry per job, in the same
+    /// order as *jobs*. A successful entry hands back the destination along
+    /// with the number of bytes written to it. One failed transfer does not
+    /// affect the others. Progress is reported to *nbe*, with each transfer
+    /// identified by the index of its job.
+    pub fn download_many<W: Write, B: NotificationBackend>(&mut self, jobs: Vec<(String, W)>, nbe: &mut B)
+                                                          -> Vec<Result<(W, u64)>>
+    {
+        self.run_many(jobs, |dest, _| Ok(dest), fetch, |_, result, _| result, nbe)
+    }
+
+    /// Run a batch of downloads concurrently into destinations that may be
+    /// able to take the contents of local files without copying them.
+    ///
+    /// This is to `download_many()` as `download_cloning()` is to
+    /// `download()`, except that the destinations are created on demand.
+    /// Each job is a URL and a value that *open* turns into a destination
+    /// when the job's transfer is about to start. Likewise, *finish* is
+    /// handed the index of each job and its outcome as soon as the transfer
+    /// is over, so that the destination can be dealt with right away. This
+    /// way no more than `max_concurrent` destinations exist at any one time.
+    /// Both closures are also given the notification backend. If *open*
+    /// fails, its error is passed on to *finish*. The returned vector holds
+    /// whatever *finish* returns, in the same order as *jobs*.
+    pub fn download_many_cloning<J, D, R, O, C, B>(&mut self, jobs: Vec<(String, J)>, open: O, finish: C,
+                                                  nbe: &mut B) -> Vec<R>
+        where D: ResumableDest, O: FnMut(J, &mut B) -> Result<D>, C: FnMut(usize, Result<(D, u64)>, &mut B) -> R,
+              B: NotificationBackend
+    {
+        self.run_many(jobs, open, fetch_cloning, finish, nbe)
+    }
+
+    /// Run a batch of downloads concurrently, resuming earlier transfers
+    /// where possible.
+    ///
+    /// This works like `download_many_cloning()`, except that if a
+    /// destination already holds the beginning of its data, the server is
+    /// asked to send only the rest. If the server can't or won't do that, or the resource
+    /// has changed since the earlier transfer, the download starts over from
+    /// the beginning. The byte count handed to *finish* includes the data
+    /// that the destination held beforehand. Because they can pick up where
+    /// they left off, these transfers are retried even if they fail partway
+    /// through.
+    pub fn download_many_resumable<J, D, R, O, C, B>(&mut self, jobs: Vec<(String, J)>, open: O, finish: C,
+                                                    nbe: &mut B) -> Vec<R>
+        where D: ResumableDest, O: FnMut(J, &mut B) -> Result<D>, C: FnMut(usize, Result<(D, u64)>, &mut B) -> R,
+              B: NotificationBackend
+    {
+        self.run_many(jobs, open, fetch_resumable, finish, nbe)
+    }
+
+    /// The common implementation of the batch download functions.
+    ///
+    /// We do our own scheduling, rather than using something like
+    /// `buffer_unordered()`, so that destinations are only opened as slots
+    /// free up and are let go of as soon as their transfers end, and so that
+    /// *open* and *finish* can be given the notification backend.
+    fn run_many<'a, J, D: 'a, R, O, F, C, B>(&mut self, jobs: Vec<(String, J)>, mut open: O, attempt: F,
+                                             mut finish: C, nbe: &mut B) -> Vec<R>
+        where O: FnMut(J, &mut B) -> Result<D>, F: Fn(&Context, &str, D) -> Attempt<'a, D> + Copy + 'a,
+              C: FnMut(usize, Result<(D, u64)>, &mut B) -> R, B: NotificationBackend
+    {
+        let cx = &self.cx;
+        let max_concurrent = self.max_concurrent;
+        let events = cx.progress.events.clone();
+        let mut jobs = jobs.into_iter().enumerate();
+        let mut active = stream::FuturesUnordered::new();
+        let mut results = Vec::new();
+
+        // Failures are captured per-transfer, so the combined future can't
+        // fail.
+        let _ = self.core.run(future::poll_fn(|| -> Poll<(), ()> {
+            loop {
+                while active.len() < max_concurrent {
+                    let (i, (uri, job)) = match jobs.next() {
+                        Some(j) => j,
+                        None => { break; },
+                    };
+
+                    match open(job, nbe) {
+                        Ok(dest) => {
+                            active.push(with_retries(cx, i, &uri, dest, attempt).then(move |r| Ok::<_, ()>((i, r))));
+                        },
+                        Err(e) => {
+                            let r = finish(i, Err(e), nbe);
+                            results.push((i, r));
+                        },
+                    }
+                }
+
+                let polled = active.poll();
+
+                for event in events.borrow_mut().drain(..) {
+                    event.send(nbe);
+                }
+
+                match polled {
+                    Ok(Async::Ready(Some((i, r)))) => {
+                        let r = finish(i, r, nbe);
+                        results.push((i, r));
+                    },
+                    Ok(Async::Ready(None)) => { return Ok(Async::Ready(())); },
+                    Ok(Async::NotReady) => { return Ok(Async::NotReady); },
+                    Err(()) => { return Err(()); },
+                }
+            }
+        }));
+
+        results.sort_by_key(|&(i, _)| i);
+        results.into_iter().map(|(_, r)| r).collect()
+    }
+}
+
+
+/// Drive *fut* to completion on *core*, passing the progress events queued
+/// up by *progress* and its clones along to *nbe* as they come in.
+///
+/// Everything runs in one task, so whenever any transfer makes headway we
+/// get polled and can forward its events.
+fn run<F: Future, B: NotificationBackend>(core: &mut Core, progress: &Reporter, mut fut: F, nbe: &mut B)
+                                          -> StdResult<F::Item, F::Error>
+{
+    let events = progress.events.clone();
+
+    core.run(future::poll_fn(|| {
+        let result = fut.poll();
+
+        for event in events.borrow_mut().drain(..) {
+            event.send(nbe);
+        }
+
+        result
+    }))
+}
+
+
+/// Create a Hyper client that will route connections through *connector*.
+fn make_client(connector: &HttpsConnector) -> HttpsClient {
+    Client::configure()
+        .connector(connector.clone())
+        .build(&connector.handle)
+}
+
+
+/// A destination for downloads that may already hold the beginning of the
+/// data from an earlier, interrupted transfer.
+pub trait ResumableDest: Write {
+    /// Get the number of bytes already held, and the validator (that is, the
+    /// HTTP entity tag) of the resource that they came from, if one was
+    /// recorded.
+    fn resume_point(&self) -> Option<(u64, Option<String>)>;
+
+    /// Called when the server has agreed to send only the rest of the data.
+    /// Subsequent writes continue on from the data already held.
+    fn resume(&mut self) -> Result<()>;
+
+    /// Called when the transfer has to start over from the beginning. Any
+    /// data already held must be discarded.
+    fn restart(&mut self) -> Result<()>;
+
+    /// Record the validator of the resource being downloaded, so that a
+    /// later transfer can check that it hasn't changed.
+    fn set_validator(&mut self, validator: Option<&str>) -> Result<()>;
+
+    /// Try to fill the destination with the contents of the local file
+    /// *src* without copying them, as filesystems that support reflinks
+    /// can. Any data already held are discarded.
+    ///
+    /// Returns the number of bytes now held, or None if this isn't possible,
+    /// in which case the data are copied in the ordinary way.
+    fn clone_file(&mut self, _src: &mut File) -> Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+impl<'a, D: ResumableDest + ?Sized> ResumableDest for &'a mut D {
+    fn resume_point(&self) -> Option<(u64, Option<String>)> {
+        (**self).resume_point()
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        (**self).resume()
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        (**self).restart()
+    }
+
+    fn set_validator(&mut self, validator: Option<&str>) -> Result<()> {
+        (**self).set_validator(validator)
+    }
+
+    fn clone_file(&mut self, src: &mut File) -> Result<Option<u64>> {
+        (**self).clone_file(src)
+    }
+}
+
+
+/// A download in progress, resolving to its destination and the number of
+/// bytes that the destination holds.
+type Transfer<'a, D> = Box<Future<Item = (D, u64), Error = Error> + 'a>;
+
+/// A single attempt at a download. If it fails, the destination is handed
+/// back so that another attempt can be made.
+type Attempt<'a, D> = Box<Future<Item = (D, u64), Error = Failure<D>> + 'a>;
+
+/// The point from which a download should be resumed: an offset and the
+/// entity tag of the resource, if known.
+type ResumePoint = Option<(u64, Option<EntityTag>)>;
+
+
+/// An error encountered during a transfer.
+struct TransferError {
+    error: Error,
+
+    /// Whether the error might go away if the transfer is tried again.
+    transient: bool,
+}
+
+impl TransferError {
+    fn transient<E: Into<Error>>(e: E) -> Self {
+        TransferError { error: e.into(), transient: true }
+    }
+
+    fn fatal<E: Into<Error>>(e: E) -> Self {
+        TransferError { error: e.into(), transient: false }
+    }
+
+    /// Create an error for an HTTP response with an unsuccessful status.
+    ///
+    /// Server errors and rate limiting are considered transient.
+    fn from_status(uri: &str, status: StatusCode) -> Self {
+        TransferError {
+            error: ErrorKind::Msg(format!("failed to download {}: got non-successful HTTP status {}",
+                                          auth::redact_url(uri), status)).into(),
+            transient: status.is_server_error() || status == StatusCode::TooManyRequests,
+        }
+    }
+
+    /// Create an error for a server that has gone quiet for too long.
+    fn timed_out(limit: Duration, what: &str) -> Self {
+        TransferError::transient(ErrorKind::Msg(format!("timed out after {} seconds waiting for {}",
+                                                        limit.as_secs(), what)))
+    }
+}
+
+
+/// A failed attempt at a transfer.
+struct Failure<D> {
+    error: TransferError,
+    dest: D,
+}
+
+
+/// Run attempts at a transfer until one succeeds, one fails for a reason
+/// that isn't transient, or we run out of retries.
+///
+/// This is where URL rewrite rules are applied, so every download path goes
+/// through it. Progress is reported under the identifier *id*.
+fn with_retries<'a, D: 'a, F>(cx: &Context, id: usize, uri: &str, dest: D, attempt: F) -> Transfer<'a, D>
+    where F: Fn(&Context, &str, D) -> Attempt<'a, D> + 'a
+{
+    let mut cx = cx.clone();
+    cx.progress.id = id;
+    cx.progress.what = auth::redact_url(uri);
+    let progress = cx.progress.clone();
+    let uri = cx.rewrites.rewrite(uri);
+
+    // Local files are fine in offline mode.
+    if cx.offline && !file::is_file_url(&uri) {
+        return Box::new(future::err(ErrorKind::Offline(auth::redact_url(&uri)).into()));
+    }
+
+    Box::new(future::loop_fn((dest, 0), move |(dest, n_retries)| {
+        let handle = cx.handle.clone();
+        let max_retries = cx.retries;
+        let uri = uri.clone();
+
+        attempt(&cx, &uri, dest).then(move |r| -> Box<Future<Item = Loop<(D, u64), (D, u32)>, Error = Error> + 'a> {
+            let failure = match r {
+                Ok(done) => { return Box::new(future::ok(Loop::Break(done))); },
+                Err(f) => f,
+            };
+
+            if !failure.error.transient || n_retries >= max_retries {
+                let e = failure.error.error;
+
+                if n_retries == 0 {
+                    return Box::new(future::err(e));
+                }
+
+                return Box::new(future::err(Error::with_chain(e, format!("giving up on {} after {} attempts",
+                                                                          auth::redact_url(&uri), n_retries + 1))));
+            }
+
+            let delay = Duration::from_secs(cmp::min(INITIAL_BACKOFF << cmp::min(n_retries, 16), MAX_BACKOFF));
+
+            match Timeout::new(delay, &handle) {
+                Ok(t) => Box::new(t.map_err(Error::from).map(move |_| Loop::Continue((failure.dest, n_retries + 1)))),
+                Err(e) => Box::new(future::err(e.into())),
+            }
+        })
+    }).then(move |r| {
+        progress.end();
+        r
+    }))
+}
+
+
+/// Make a future fail if it doesn't complete within *limit*.
+fn with_timeout<F>(fut: F, limit: Option<Duration>, handle: &Handle, what: &'static str)
+                   -> Box<Future<Item = F::Item, Error = TransferError>>
+    where F: Future<Error = TransferError> + 'static
+{
+    let limit = match limit {
+        Some(l) => l,
+        None => { return Box::new(fut); },
+    };
+
+    let expired = match Timeout::new(limit, handle) {
+        Ok(t) => t.then(move |_| Err(TransferError::timed_out(limit, what))),
+        Err(e) => { return Box::new(future::err(TransferError::fatal(e))); },
+    };
+
+    Box::new(fut.select(expired).map(|(item, _)| item).map_err(|(e, _)| e))
+}
+
+
+/// Send a GET request for *uri*, following redirections.
+///
+/// If *resume* is provided, the server is asked to send only the data
+/// starting at the specified offset, and only if the resource still matches
+/// the specified entity tag. The returned future resolves to the first
+/// response that isn't a redirection; its status is not otherwise checked.
+/// This will just fetch the headers; the body won't be downloaded yet.
+fn send(cx: &Context, uri: &str, resume: ResumePoint) -> Box<Future<Item = Response, Error = TransferError>> {
+    // Credentials embedded in the URL are sent in a header instead.
+    let (orig_uri, inline_creds) = match auth::split_credentials(uri) {
+        Ok(split) => split,
+        Err(e) => { return Box::new(future::err(TransferError::fatal(e))); },
+    };
+
+    let parsed: Uri = match orig_uri.parse() {
+        Ok(u) => u,
+        Err(e) => { return Box::new(future::err(TransferError::fatal(e))); },
+    };
+
+    let cx = cx.clone();
+    let orig_host = parsed.host().map(|h| h.to_lowercase());
+
+    Box::new(future::loop_fn((parsed, 0), move |(parsed, n_redirects)| {
+        let orig_uri = orig_uri.clone();
+        let mut req = Request::new(Method::Get, parsed.clone());
+        cx.auth.apply(&parsed, req.headers_mut());
+
+        // Only hand the embedded credentials to the host that they were
+        // meant for, not to whatever it redirects us to.
+        if let Some(ref creds) = inline_creds {
+            if parsed.host().map(|h| h.to_lowercase()) == orig_host {
+                auth::set_basic(req.headers_mut(), creds);
+            }
+        }
+
+        // Plain HTTP requests that go through a proxy are sent to it with the
+        // full URI of the resource.
+        if parsed.scheme() == Some("http") {
+            if let Some(proxy) = cx.proxies.proxy_for(&parsed) {
+                req.set_proxy(true);
+
+                if let Some(auth) = proxy.authorization() {
+                    req.headers_mut().set(auth);
+                }
+            }
+        }
+
+        if let Some((offset, ref tag)) = resume {
+            req.headers_mut().set(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]));
+
+            // If-Range only works with strong validators.
+            if let Some(ref t) = *tag {
+                if !t.weak {
+                    req.headers_mut().set(IfRange::EntityTag(t.clone()));
+                }
+            }
+        }
+
+        let response = with_timeout(cx.client.request(req).map_err(TransferError::transient),
+                                    cx.read_timeout, &cx.handle, "a response");
+
+        response.and_then(move |response| {
+            if !response.status().is_redirection() {
+                return Ok(Loop::Break(response));
+            }
+
+            if n_redirects >= MAX_REDIRECTS {
+                return Err(TransferError::fatal(ErrorKind::Msg(format!("failed to download {}: too many redirection",
+                                                                       orig_uri))));
+            }
+
+            let next: Uri = match response.headers().get::<Location>() {
+                Some(h) => h.parse().map_err(TransferError::fatal)?,
+                None => {
+                    return Err(TransferError::fatal(ErrorKind::Msg(format!("illegal redirect from {}: no Location header",
+                                                                           auth::redact_url(&parsed.to_string())))));
+                },
+            };
+            Ok(Loop::Continue((next, n_redirects + 1)))
+        })
+    }))
+}
+
+
+/// A future that streams the body of a response into a destination.
+///
+/// It resolves to the destination and the number of bytes that it holds.
+struct Receive<W> {
+    body: Body,
+    dest: Option<W>,
+    n_bytes: u64,
+    n_received: u64,
+    idle_timeout: Option<(Duration, Timeout)>,
+    restartable: bool,
+    progress: Reporter,
+}
+
+impl<W> Receive<W> {
+    /// Create a future that streams the body of *response* into *dest*.
+    ///
+    /// *n_initial* is the number of bytes that *dest* already holds. If
+    /// *restartable* is false, the transfer can't be retried once any data
+    /// have been written to *dest*.
+    fn new(cx: &Context, response: Response, dest: W, n_initial: u64, restartable: bool) -> StdResult<Self, Failure<W>> {
+        let idle_timeout = match cx.read_timeout {
+            Some(limit) => match Timeout::new(limit, &cx.handle) {
+                Ok(t) => Some((limit, t)),
+                Err(e) => { return Err(Failure { error: TransferError::fatal(e), dest: dest }); },
+            },
+            None => None,
+        };
+
+        // For a resumed transfer, the length is that of the remaining data.
+        let total = response.headers().get::<ContentLength>().map(|l| l.0 + n_initial);
+        cx.progress.start(n_initial, total);
+
+        Ok(Receive {
+            body: response.body(),
+            dest: Some(dest),
+            n_bytes: n_initial,
+            n_received: 0,
+            idle_timeout: idle_timeout,
+            restartable: restartable,
+            progress: cx.progress.clone(),
+        })
+    }
+
+    fn fail(&mut self, mut error: TransferError) -> Failure<W> {
+        // Trying again would put duplicate data into the destination.
+        if self.n_received > 0 && !self.restartable {
+            error.transient = false;
+        }
+
+        Failure {
+            error: error,
+            dest: self.dest.take().unwrap(),
+        }
+    }
+}
+
+impl<W: Write> Future for Receive<W> {
+    type Item = (W, u64);
+    type Error = Failure<W>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.body.poll() {
+                Ok(Async::Ready(Some(chunk))) => {
+                    if let Err(e) = self.dest.as_mut().unwrap().write_all(&chunk) {
+                        return Err(self.fail(TransferError::fatal(e)));
+                    }
+
+                    self.n_bytes += chunk.len() as u64;
+                    self.n_received += chunk.len() as u64;
+                    self.progress.update(self.n_bytes);
+
+                    if let Some((limit, ref mut t)) = self.idle_timeout {
+                        t.reset(Instant::now() + limit);
+                    }
+                },
+                Ok(Async::Ready(None)) => {
+                    return Ok(Async::Ready((self.dest.take().unwrap(), self.n_bytes)));
+                },
+                Ok(Async::NotReady) => { break; },
+                Err(e) => { return Err(self.fail(TransferError::transient(e))); },
+            }
+        }
+
+        let expired = match self.idle_timeout {
+            Some((limit, ref mut t)) => match t.poll() {
+                Ok(Async::Ready(_)) => Some(TransferError::timed_out(limit, "more data")),
+                Ok(Async::NotReady) => None,
+                Err(e) => Some(TransferError::fatal(e)),
+            },
+            None => None,
+        };
+
+        match expired {
+            Some(e) => Err(self.fail(e)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+
+/// Make a single attempt at downloading *uri* into *dest*.
+fn fetch<'a, W: Write + 'a>(cx: &Context, uri: &str, dest: W) -> Attempt<'a, W> {
+    if file::is_file_url(uri) {
+        return fetch_file(cx, uri, dest, |_, _| Ok(None));
+    }
+
+    let cx = cx.clone();
+    let orig_uri = uri.to_owned();
+
+    Box::new(send(&cx, uri, None).then(move |r| -> Attempt<'a, W> {
+        let response = match r {
+            Ok(r) => r,
+            Err(e) => { return Box::new(future::err(Failure { error: e, dest: dest })); },
+        };
+
+        let status = response.status();
+
+        if !status.is_success() {
+            return Box::new(future::err(Failure { error: TransferError::from_status(&orig_uri, status), dest: dest }));
+        }
+
+        match Receive::new(&cx, response, dest, 0, false) {
+            Ok(r) => Box::new(r),
+            Err(f) => Box::new(future::err(f)),
+        }
+    }))
+}
+
+
+/// Make a single attempt at downloading *uri* into *dest*, resuming an
+/// earlier transfer if possible.
+fn fetch_resumable<'a, D: ResumableDest + 'a>(cx: &Context, uri: &str, mut dest: D) -> Attempt<'a, D> {
+    if file::is_file_url(uri) {
+        // Local copies are cheap enough that there's no point in resuming
+        // them.
+        return fetch_file(cx, uri, dest, |src, dest| {
+            if let Some(n_bytes) = dest.clone_file(src)? {
+                return Ok(Some(n_bytes));
+            }
+
+            dest.restart()?;
+            Ok(None)
+        });
+    }
+
+    let resume = dest.resume_point().map(|(offset, validator)| {
+        (offset, validator.and_then(|v| v.parse::<EntityTag>().ok()))
+    });
+    let cx = cx.clone();
+    let orig_uri = uri.to_owned();
+
+    Box::new(send(&cx, uri, resume.clone()).then(move |r| -> Attempt<'a, D> {
+        let response = match r {
+            Ok(r) => r,
+            Err(e) => { return Box::new(future::err(Failure { error: e, dest: dest })); },
+        };
+
+        match check_resumed(&orig_uri, &response, &resume, &mut dest) {
+            Ok(Some(n_initial)) => match Receive::new(&cx, response, dest, n_initial, true) {
+                Ok(r) => Box::new(r),
+                Err(f) => Box::new(future::err(f)),
+            },
+            Ok(None) => fetch_resumable(&cx, &orig_uri, dest),
+            Err(e) => Box::new(future::err(Failure { error: e, dest: dest })),
+        }
+    }))
+}
+
+
+/// Make a single attempt at downloading *uri* into *dest*, which must be
+/// empty.
+///
+/// This is like `fetch()`, except that data from `file://` URLs are cloned
+/// into *dest* rather than copied, if it supports that.
+fn fetch_cloning<'a, D: ResumableDest + 'a>(cx: &Context, uri: &str, dest: D) -> Attempt<'a, D> {
+    if file::is_file_url(uri) {
+        return fetch_file(cx, uri, dest, |src, dest| dest.clone_file(src));
+    }
+
+    fetch(cx, uri, dest)
+}
+
+
+/// Copy the file named by a `file://` URL into *dest*.
+///
+/// First, *prepare* gets a chance to fill *dest* some cheaper way, returning
+/// the number of bytes that it now holds if it succeeds. Otherwise the data
+/// are copied. Failures are never transient.
+fn fetch_file<'a, D, F>(cx: &Context, uri: &str, mut dest: D, prepare: F) -> Attempt<'a, D>
+    where D: Write + 'a, F: FnOnce(&mut File, &mut D) -> Result<Option<u64>>
+{
+    let result = file::open(uri).and_then(|mut src| {
+        cx.progress.start(0, src.metadata().ok().map(|m| m.len()));
+        let prepared = prepare(&mut src, &mut dest)?;
+        Ok((src, prepared))
+    });
+
+    match result {
+        Ok((_, Some(n_bytes))) => {
+            cx.progress.update(n_bytes);
+            Box::new(future::ok((dest, n_bytes)))
+        },
+        Ok((src, None)) => Box::new(CopyFile {
+            src: src,
+            dest: Some(dest),
+            buf: vec![0u8; file::COPY_BUFFER_SIZE],
+            n_bytes: 0,
+            progress: cx.progress.clone(),
+        }),
+        Err(e) => Box::new(future::err(Failure { error: TransferError::fatal(e), dest: dest })),
+    }
+}
+
+
+/// A future that copies a local file into a destination.
+///
+/// The copying is done one buffer at a time, yielding to the event loop in
+/// between, so that progress can be reported and other transfers aren't
+/// held up.
+struct CopyFile<D> {
+    src: File,
+    dest: Option<D>,
+    buf: Vec<u8>,
+    n_bytes: u64,
+    progress: Reporter,
+}
+
+impl<D: Write> Future for CopyFile<D> {
+    type Item = (D, u64);
+    type Error = Failure<D>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.src.read(&mut self.buf).and_then(|n| {
+            self.dest.as_mut().unwrap().write_all(&self.buf[..n])?;
+            Ok(n)
+        });
+
+        match result {
+            Ok(0) => Ok(Async::Ready((self.dest.take().unwrap(), self.n_bytes))),
+            Ok(n) => {
+                self.n_bytes += n as u64;
+                self.progress.update(self.n_bytes);
+                task::current().notify();
+                Ok(Async::NotReady)
+            },
+            Err(e) => Err(Failure { error: TransferError::fatal(e), dest: self.dest.take().unwrap() }),
+        }
+    }
+}
+
+
+/// Figure out what to do with the response to a possibly-resumed download.
+///
+/// Returns the number of bytes of the data that *dest* already holds, or
+/// None if the request must be repeated without trying to resume.
+fn check_resumed<D: ResumableDest>(uri: &str, response: &Response, resume: &ResumePoint, dest: &mut D)
+                                   -> StdResult<Option<u64>, TransferError>
+{
+    let status = response.status();
+    let etag = response.headers().get::<ETag>().map(|h| h.0.clone());
+
+    let n_initial = match *resume {
+        Some(_) if status == StatusCode::RangeNotSatisfiable => {
+            // Our partial data don't fit the resource; maybe it has shrunk.
+            dest.restart().map_err(TransferError::fatal)?;
+            return Ok(None);
+        },
+        Some((offset, ref old_tag)) if status == StatusCode::PartialContent => {
+            let start = match response.headers().get::<ContentRange>() {
+                Some(&ContentRange(ContentRangeSpec::Bytes { range: Some((start, _)), .. })) => Some(start),
+                _ => None,
+            };
+
+            let changed = match (old_tag, &etag) {
+                (&Some(ref old), &Some(ref new)) => old != new,
+                _ => false,
+            };
+
+            if start != Some(offset) || changed {
+                dest.restart().map_err(TransferError::fatal)?;
+                return Ok(None);
+            }
+
+            dest.resume().map_err(TransferError::fatal)?;
+            offset
+        },
+        None if status == StatusCode::PartialContent => {
+            // We asked for everything, so there's no telling which part this
+            // is.
+            return Err(TransferError::fatal(ErrorKind::Msg(format!(
+                "failed to download {}: the server sent partial content that wasn\'t asked for",
+                auth::redact_url(uri)))));
+        },
+        _ if status.is_success() => {
+            // Either we didn't ask for a range, or the server ignored our
+            // request and is sending everything.
+            if resume.is_some() {
+                dest.restart().map_err(TransferError::fatal)?;
+            }
+
+            0
+        },
+        _ => {
+            return Err(TransferError::from_status(uri, status));
+        },
+    };
+
+    let validator = etag.map(|t| t.to_string());
+    dest.set_validator(validator.as_ref().map(|s| s.as_ref())).map_err(TransferError::fatal)?;
+    Ok(Some(n_initial))
+}
+
+
+/// Determine whether the environment asks for offline mode.
+///
+/// See `OFFLINE_ENV_VAR`.
+pub fn offline_from_env() -> bool {
+    match env::var_os(OFFLINE_ENV_VAR) {
+        Some(v) => v.len() > 0 && v != "0",
+        None => false,
+    }
+}
+
+
+/// Download over HTTP or HTTPS into a Write object.
+///
+/// This is a convenience wrapper that sets up a new Downloader for a single
+/// transfer. Progress is reported to *nbe*.
+pub fn download<W: Write, B: NotificationBackend>(uri: &str, dest: W, nbe: &mut B) -> Result<u64> {
+    Downloader::new()?.download(uri, dest, nbe)
+}
+
+
+/// Decode percent-encoded characters in a string.
+fn percent_decode(text: &str) -> Result<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let value = bytes.get(i+1..i+3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+
+        match value {
+            Some(v) => { decoded.push(v); },
+            None => { return err_msg!("invalid percent-encoding in \"{}\"", text); },
+        }
+
+        i += 3;
+    }
+
+    Ok(ctry!(String::from_utf8(decoded); "percent-encoded text is not valid UTF-8"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A destination that just keeps track of what is done to it.
+    #[derive(Debug, Default)]
+    struct MockDest {
+        restarted: bool,
+        resumed: bool,
+        validator: Option<String>,
+    }
+
+    impl Write for MockDest {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ResumableDest for MockDest {
+        fn resume_point(&self) -> Option<(u64, Option<String>)> {
+            None
+        }
+
+        fn resume(&mut self) -> Result<()> {
+            self.resumed = true;
+            Ok(())
+        }
+
+        fn restart(&mut self) -> Result<()> {
+            self.restarted = true;
+            Ok(())
+        }
+
+        fn set_validator(&mut self, validator: Option<&str>) -> Result<()> {
+            self.validator = validator.map(|v| v.to_owned());
+            Ok(())
+        }
+    }
+
+    fn response(status: StatusCode, start: Option<u64>, etag: Option<&str>) -> Response {
+        let mut r = Response::new().with_status(status);
+
+        if let Some(s) = start {
+            r.headers_mut().set(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((s, 99)),
+                instance_length: Some(100),
+            }));
+        }
+
+        if let Some(t) = etag {
+            r.headers_mut().set(ETag(EntityTag::strong(t.to_owned())));
+        }
+
+        r
+    }
+
+    /// Run `check_resumed()`, returning the outcome and what happened to the
+    /// destination. Errors are reduced to whether they're transient.
+    fn check(resume: ResumePoint, r: Response) -> (StdResult<Option<u64>, bool>, MockDest) {
+        let mut dest = MockDest::default();
+        let outcome = check_resumed("https://example.com/a", &r, &resume, &mut dest).map_err(|e| e.transient);
+        (outcome, dest)
+    }
+
+    fn tag(t: &str) -> Option<EntityTag> {
+        Some(EntityTag::strong(t.to_owned()))
+    }
+
+    #[test]
+    fn fresh_download() {
+        let (outcome, dest) = check(None, response(StatusCode::Ok, None, Some("v1")));
+        assert_eq!(outcome, Ok(Some(0)));
+        assert!(!dest.restarted && !dest.resumed);
+        assert_eq!(dest.validator, Some("\"v1\"".to_owned()));
+
+        let (outcome, _) = check(None, response(StatusCode::PartialContent, Some(0), None));
+        assert_eq!(outcome, Err(false));
+
+        let (outcome, _) = check(None, response(StatusCode::RangeNotSatisfiable, None, None));
+        assert_eq!(outcome, Err(false));
+
+        let (outcome, _) = check(None, response(StatusCode::ServiceUnavailable, None, None));
+        assert_eq!(outcome, Err(true));
+    }
+
+    #[test]
+    fn resumed_download() {
+        let (outcome, dest) = check(Some((10, tag("v1"))), response(StatusCode::PartialContent, Some(10), Some("v1")));
+        assert_eq!(outcome, Ok(Some(10)));
+        assert!(dest.resumed && !dest.restarted);
+
+        let (outcome, dest) = check(Some((10, None)), response(StatusCode::PartialContent, Some(10), None));
+        assert_eq!(outcome, Ok(Some(10)));
+        assert!(dest.resumed && !dest.restarted);
+    }
+
+    #[test]
+    fn resume_refused() {
+        // The server ignores the range and sends everything.
+        let (outcome, dest) = check(Some((10, None)), response(StatusCode::Ok, None, None));
+        assert_eq!(outcome, Ok(Some(0)));
+        assert!(dest.restarted && !dest.resumed);
+
+        // The partial data don't fit the resource.
+        let (outcome, dest) = check(Some((10, None)), response(StatusCode::RangeNotSatisfiable, None, None));
+        assert_eq!(outcome, Ok(None));
+        assert!(dest.restarted);
+
+        // The wrong part is sent.
+        let (outcome, dest) = check(Some((10, None)), response(StatusCode::PartialContent, Some(20), None));
+        assert_eq!(outcome, Ok(None));
+        assert!(dest.restarted && !dest.resumed);
+
+        // The resource has changed.
+        let (outcome, dest) = check(Some((10, tag("v1"))), response(StatusCode::PartialContent, Some(10), Some("v2")));
+        assert_eq!(outcome, Ok(None));
+        assert!(dest.restarted && !dest.resumed);
+
+        let (outcome, _) = check(Some((10, None)), response(StatusCode::NotFound, None, None));
+        assert_eq!(outcome, Err(false));
+    }
+
+    #[test]
+    fn percent_decode_plain() {
+        assert_eq!(percent_decode("").unwrap(), "");
+        assert_eq!(percent_decode("plain text").unwrap(), "plain text");
+    }
+
+    #[test]
+    fn percent_decode_escapes() {
+        assert_eq!(percent_decode("a%20b%2fc%2F").unwrap(), "a b/c/");
+        assert_eq!(percent_decode("%25%40%3A").unwrap(), "%@:");
+        assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café");
+    }
+
+    #[test]
+    fn percent_decode_invalid() {
+        assert!(percent_decode("%").is_err());
+        assert!(percent_decode("abc%4").is_err());
+        assert!(percent_decode("%g0").is_err());
+        assert!(percent_decode("%+1").is_err());
+        assert!(percent_decode("%C3").is_err());
+    }
+}
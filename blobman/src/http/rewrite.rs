@@ -0,0 +1,103 @@
+// Copyright 2017 Peter Williams and collaborators
+// Licensed under the MIT License.
+
+/*!
+Rewriting URLs before they are downloaded.
+
+This works like Git’s `url.<base>.insteadOf` setting: any URL starting with
+one of the configured prefixes is downloaded from the corresponding
+replacement location instead. The original URL is what gets recorded in the
+manifest, so this is a way to redirect downloads to a local mirror without
+changing anything that is shared with other people.
+
+*/
+
+
+/// A set of prefix-based URL rewrite rules.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UrlRewrites {
+    rules: Vec<(String, String)>,
+}
+
+
+impl UrlRewrites {
+    /// Create a set of rules that leaves every URL alone.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule that replaces the prefix *prefix* with *replacement*.
+    pub fn add_rule(&mut self, prefix: &str, replacement: &str) -> &mut Self {
+        self.rules.push((prefix.to_owned(), replacement.to_owned()));
+        self
+    }
+
+    /// Apply the rules to *url*.
+    ///
+    /// As with Git, if several prefixes match, the longest one wins, and
+    /// prefixes are compared case-sensitively. The result is not rewritten
+    /// again.
+    pub fn rewrite(&self, url: &str) -> String {
+        let mut best: Option<&(String, String)> = None;
+
+        for rule in &self.rules {
+            if url.starts_with(&rule.0) && best.map(|b| rule.0.len() > b.0.len()).unwrap_or(true) {
+                best = Some(rule);
+            }
+        }
+
+        match best {
+            Some(&(ref prefix, ref replacement)) => format!("{}{}", replacement, &url[prefix.len()..]),
+            None => url.to_owned(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> UrlRewrites {
+        let mut r = UrlRewrites::new();
+        r.add_rule("https://example.com/", "https://mirror.example.net/");
+        r.add_rule("https://example.com/big/", "file:///srv/big/");
+        r.add_rule("https://example.org/data.tar", "file:///srv/data.tar");
+        r
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let r = rules();
+        assert_eq!(r.rewrite("https://example.com/big/a.dat"), "file:///srv/big/a.dat");
+        assert_eq!(r.rewrite("https://example.com/small/a.dat"), "https://mirror.example.net/small/a.dat");
+
+        // The order in which the rules were added doesn't matter.
+        let mut r = UrlRewrites::new();
+        r.add_rule("https://example.com/big/", "file:///srv/big/");
+        r.add_rule("https://example.com/", "https://mirror.example.net/");
+        assert_eq!(r.rewrite("https://example.com/big/a.dat"), "file:///srv/big/a.dat");
+    }
+
+    #[test]
+    fn no_match() {
+        let r = rules();
+        assert_eq!(r.rewrite("https://example.net/a.dat"), "https://example.net/a.dat");
+        assert_eq!(r.rewrite("https://example.com"), "https://example.com");
+        assert_eq!(UrlRewrites::new().rewrite("https://example.com/a.dat"), "https://example.com/a.dat");
+    }
+
+    #[test]
+    fn exact_prefix() {
+        let r = rules();
+        assert_eq!(r.rewrite("https://example.org/data.tar"), "file:///srv/data.tar");
+        assert_eq!(r.rewrite("https://example.com/"), "https://mirror.example.net/");
+    }
+
+    #[test]
+    fn case_sensitive() {
+        let r = rules();
+        assert_eq!(r.rewrite("HTTPS://EXAMPLE.COM/a.dat"), "HTTPS://EXAMPLE.COM/a.dat");
+        assert_eq!(r.rewrite("https://example.com/BIG/a.dat"), "https://mirror.example.net/BIG/a.dat");
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright 2017 Peter Williams and collaborators
+// Licensed under the MIT License.
+
+/*!
+Customizing how TLS connections are secured.
+
+By default, HTTPS servers are authenticated against the platform’s standard
+trust store and we don’t identify ourselves to them. The settings here make it
+possible to trust additional root certificates, present a client certificate
+to servers that require one, and, for testing, turn off certificate
+verification altogether.
+
+*/
+
+use native_tls::{Certificate, Pkcs12, TlsConnector};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use errors::Result;
+
+
+const PEM_BEGIN: &'static str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &'static str = "-----END CERTIFICATE-----";
+
+
+/// A certificate and private key that we use to identify ourselves to
+/// servers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Identity {
+    /// A PKCS#12 archive containing both the certificate and the key.
+    Pkcs12 {
+        /// The path to the archive.
+        path: PathBuf,
+
+        /// The password used to decrypt the archive.
+        password: String,
+    },
+
+    /// A pair of PEM files. The certificate file may also contain
+    /// intermediate certificates, which are sent along to the server.
+    ///
+    /// This form is only supported on platforms that use OpenSSL.
+    Pem {
+        /// The path to the certificate file.
+        certificate: PathBuf,
+
+        /// The path to the private key file, which must not be encrypted.
+        key: PathBuf,
+    },
+}
+
+
+impl Identity {
+    /// Load the identity into the form that native-tls wants.
+    fn load(&self) -> Result<Pkcs12> {
+        match *self {
+            Identity::Pkcs12 { ref path, ref password } => {
+                let buf = read_file(path)?;
+                Ok(ctry!(Pkcs12::from_der(&buf, password); "couldn't decode the PKCS#12 archive {}",
+                         path.display()))
+            },
+            Identity::Pem { ref certificate, ref key } => {
+                let cert_buf = read_file(certificate)?;
+                let key_buf = read_file(key)?;
+                imp::pem_identity(&cert_buf, &key_buf)
+            },
+        }
+    }
+}
+
+
+/// Settings that control how TLS connections are set up.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TlsSettings {
+    root_certificates: Vec<PathBuf>,
+    identity: Option<Identity>,
+    insecure: bool,
+}
+
+
+impl TlsSettings {
+    /// Create settings that use the platform defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the root certificates stored in the file at *path*, in addition
+    /// to the ones that the platform trusts.
+    ///
+    /// The file may be a PEM bundle holding any number of certificates, or a
+    /// single DER-encoded certificate.
+    pub fn add_root_certificates<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.root_certificates.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Set the identity that we present to servers that ask for one.
+    pub fn set_identity(&mut self, identity: Option<Identity>) -> &mut Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Set whether to skip verification of server certificates.
+    ///
+    /// This makes it trivial for an attacker to intercept our connections, so
+    /// it should only ever be used for testing against local servers. It is
+    /// only supported on platforms that use OpenSSL.
+    pub fn set_insecure(&mut self, insecure: bool) -> &mut Self {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Determine whether server certificates go unverified.
+    pub fn is_insecure(&self) -> bool {
+        self.insecure
+    }
+
+    /// Create a TlsConnector that implements these settings.
+    pub fn build_connector(&self) -> Result<TlsConnector> {
+        let mut builder = TlsConnector::builder()?;
+
+        for path in &self.root_certificates {
+            let certs = ctry!(load_certificates(path); "couldn't load root certificates from {}", path.display());
+
+            for cert in certs {
+                builder.add_root_certificate(cert)?;
+            }
+        }
+
+        if let Some(ref identity) = self.identity {
+            builder.identity(ctry!(identity.load(); "couldn't load the TLS client identity"))?;
+        }
+
+        if self.insecure {
+            imp::disable_verification(&mut builder)?;
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+
+/// Read an entire file into memory.
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut f = ctry!(File::open(path); "couldn't open {}", path.display());
+    ctry!(f.read_to_end(&mut buf); "couldn't read {}", path.display());
+    Ok(buf)
+}
+
+
+/// Load every certificate in a file.
+///
+/// native-tls will only parse the first certificate in PEM data, so we split
+/// bundles up ourselves.
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let buf = read_file(path)?;
+
+    let text = match str::from_utf8(&buf) {
+        Ok(t) if t.contains(PEM_BEGIN) => t,
+        _ => { return Ok(vec![Certificate::from_der(&buf)?]); },
+    };
+
+    let mut certs = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find(PEM_BEGIN) {
+        let end = match rest[start..].find(PEM_END) {
+            Some(i) => start + i + PEM_END.len(),
+            None => { return err_msg!("unterminated certificate in {}", path.display()); },
+        };
+
+        certs.push(Certificate::from_pem(rest[start..end].as_bytes())?);
+        rest = &rest[end..];
+    }
+
+    Ok(certs)
+}
+
+
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+mod imp {
+    use native_tls::{Pkcs12, TlsConnectorBuilder};
+    use native_tls::backend::openssl::TlsConnectorBuilderExt;
+    use openssl::pkcs12::Pkcs12 as OpensslPkcs12;
+    use openssl::pkey::PKey;
+    use openssl::ssl::SSL_VERIFY_NONE;
+    use openssl::stack::Stack;
+    use openssl::x509::X509;
+
+    use errors::Result;
+
+    /// native-tls only accepts identities in PKCS#12 form, so we repackage
+    /// the PEM data into a temporary archive.
+    pub fn pem_identity(cert: &[u8], key: &[u8]) -> Result<Pkcs12> {
+        let mut chain = ctry!(X509::stack_from_pem(cert); "couldn't parse the client certificate");
+
+        if chain.len() == 0 {
+            return err_msg!("no certificates found in the client certificate file");
+        }
+
+        let leaf = chain.remove(0);
+        let key = ctry!(PKey::private_key_from_pem(key); "couldn't parse the client private key");
+        let mut builder = OpensslPkcs12::builder();
+
+        if chain.len() > 0 {
+            let mut ca = ctry!(Stack::new(); "couldn't allocate a certificate stack");
+
+            for cert in chain {
+                ctry!(ca.push(cert); "couldn't add an intermediate certificate");
+            }
+
+            builder.ca(ca);
+        }
+
+        let archive = ctry!(builder.build("", "blobman", &key, &leaf); "couldn't assemble the client identity");
+        let der = ctry!(archive.to_der(); "couldn't assemble the client identity");
+        Ok(Pkcs12::from_der(&der, "")?)
+    }
+
+    pub fn disable_verification(builder: &mut TlsConnectorBuilder) -> Result<()> {
+        builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+        Ok(())
+    }
+}
+
+
+#[cfg(any(target_os = "windows", target_os = "macos", target_os = "ios"))]
+mod imp {
+    use native_tls::{Pkcs12, TlsConnectorBuilder};
+
+    use errors::Result;
+
+    pub fn pem_identity(_cert: &[u8], _key: &[u8]) -> Result<Pkcs12> {
+        err_msg!("PEM client identities are not supported on this platform; use a PKCS#12 archive")
+    }
+
+    pub fn disable_verification(_builder: &mut TlsConnectorBuilder) -> Result<()> {
+        err_msg!("disabling TLS certificate verification is not supported on this platform")
+    }
+}
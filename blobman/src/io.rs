@@ -5,9 +5,12 @@
 I/O helpers.
 */
 
-use std::fs::{self, File};
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
 use std::io::ErrorKind as IoErrorKind;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
 
 use errors::Result;
 
@@ -44,3 +47,235 @@ pub fn try_remove_file<P: AsRef<Path>>(path: P) -> Result<()> {
         }
     }
 }
+
+
+/// Take out an exclusive advisory lock on a file, creating it if needed.
+///
+/// This blocks until the lock can be obtained. The lock is released when the
+/// returned File is dropped. The lock file itself is left in place, since
+/// deleting it would allow races between processes.
+pub fn lock_exclusive<P: AsRef<Path>>(path: P) -> Result<File> {
+    let path = path.as_ref();
+    let f = ctry!(OpenOptions::new().write(true).create(true).open(path);
+                  "couldn't open lock file {}", path.display());
+    ctry!(f.lock_exclusive(); "couldn't lock {}", path.display());
+    Ok(f)
+}
+
+
+/// An exclusive advisory lock whose lock file is deleted when it is released.
+///
+/// This is returned by `lock_transient()`. The lock is released when this
+/// value is dropped.
+#[derive(Debug)]
+pub struct TransientLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl Drop for TransientLock {
+    fn drop(&mut self) {
+        // We still hold the lock here, so anyone waiting on the file will
+        // notice that it has been deleted and start over.
+        if cfg!(unix) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+
+/// Take out an exclusive advisory lock on a file, creating it if needed and
+/// deleting it when the lock is released.
+///
+/// This works like `lock_exclusive()`, but doesn't leave the lock file
+/// lying around, which is nicer when it lives next to files that people
+/// care about. To avoid races, once we have the lock we check that the file
+/// wasn't deleted while we were waiting for it, and try again if it was. On
+/// platforms where we can't do that check, the lock file is left in place.
+pub fn lock_transient<P: AsRef<Path>>(path: P) -> Result<TransientLock> {
+    let path = path.as_ref();
+
+    loop {
+        let f = lock_exclusive(path)?;
+        let ours = ctry!(f.metadata(); "couldn't get info for file {}", path.display());
+
+        let current = match fs::metadata(path) {
+            Ok(md) => Some(md),
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => None,
+            Err(e) => { return Err(e.into()); },
+        };
+
+        if current.map(|md| same_file(&ours, &md)).unwrap_or(false) {
+            return Ok(TransientLock {
+                path: path.to_owned(),
+                _file: f,
+            });
+        }
+    }
+}
+
+
+/// Determine whether two sets of metadata describe the same file.
+///
+/// On platforms where we can't tell, we assume that they do.
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
+
+
+/// Atomically replace the contents of a file.
+///
+/// The data are written to a temporary file in the same directory, which is
+/// flushed to disk and then renamed on top of *path*. Therefore, if anything
+/// goes wrong, the file will either have its original contents or the new
+/// ones, but nothing in between. If the file already exists, its permissions
+/// are preserved. If it already has exactly the desired contents, nothing is
+/// done.
+///
+/// Returns true if the file was rewritten.
+pub fn atomic_write<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<bool> {
+    let path = path.as_ref();
+
+    let perms = match try_open(path)? {
+        Some(mut f) => {
+            let mut existing = Vec::new();
+            ctry!(f.read_to_end(&mut existing); "couldn't read {}", path.display());
+
+            if existing == data {
+                return Ok(false);
+            }
+
+            Some(ctry!(f.metadata(); "couldn't get info for file {}", path.display()).permissions())
+        },
+        None => None,
+    };
+
+    let file_name = match path.file_name() {
+        Some(n) => n,
+        None => { return err_msg!("cannot write to {}: not a file path", path.display()); },
+    };
+
+    let mut temp_name = file_name.to_os_string();
+    temp_name.push(format!(".tmp{}", process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = write_and_rename(&temp_path, path, data, perms);
+
+    if result.is_err() {
+        let _ = try_remove_file(&temp_path);
+    }
+
+    result.map(|_| true)
+}
+
+
+fn write_and_rename(temp_path: &Path, path: &Path, data: &[u8], perms: Option<fs::Permissions>) -> Result<()> {
+    {
+        let mut f = ctry!(OpenOptions::new().write(true).create(true).truncate(true).open(temp_path);
+                          "couldn't create {}", temp_path.display());
+        ctry!(f.write_all(data); "couldn't write {}", temp_path.display());
+        ctry!(f.sync_all(); "couldn't flush {} to disk", temp_path.display());
+    }
+
+    if let Some(p) = perms {
+        ctry!(fs::set_permissions(temp_path, p); "couldn't set permissions of {}", temp_path.display());
+    }
+
+    ctry!(fs::rename(temp_path, path); "couldn't rename {} to {}", temp_path.display(), path.display());
+
+    // On Unix, the rename itself isn't durable until the directory is synced.
+    // This is best-effort: the rename has already happened, after all.
+    #[cfg(unix)]
+    {
+        if let Some(parent) = path.parent() {
+            let dir = if parent.as_os_str().len() == 0 { Path::new(".") } else { parent };
+
+            if let Ok(d) = File::open(dir) {
+                let _ = d.sync_all();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Create an empty scratch directory for a test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("blobman-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn transient_lock_cleans_up() {
+        let dir = scratch_dir("transient-lock");
+        let path = dir.join("thing.lock");
+
+        {
+            let _lock = lock_transient(&path).unwrap();
+            assert!(path.exists());
+        }
+
+        assert_eq!(path.exists(), !cfg!(unix));
+
+        // Taking the lock again works whether or not the file was left behind.
+        drop(lock_transient(&path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_write_skips_identical_contents() {
+        use filetime::{self, FileTime};
+
+        let dir = scratch_dir("atomic-same");
+        let path = dir.join("data.txt");
+        assert!(atomic_write(&path, b"hello").unwrap());
+
+        // Backdate the file so that any rewrite would be noticed.
+        let old = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+        filetime::set_file_times(&path, old, old).unwrap();
+
+        assert!(!atomic_write(&path, b"hello").unwrap());
+        assert_eq!(FileTime::from_last_modification_time(&fs::metadata(&path).unwrap()), old);
+
+        assert!(atomic_write(&path, b"hello, world").unwrap());
+        assert!(FileTime::from_last_modification_time(&fs::metadata(&path).unwrap()) != old);
+
+        let mut text = String::new();
+        File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello, world");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_write_preserves_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("atomic-mode");
+        let path = dir.join("script.sh");
+        assert!(atomic_write(&path, b"#! /bin/sh\n").unwrap());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o751)).unwrap();
+
+        assert!(atomic_write(&path, b"#! /bin/sh\nexit 0\n").unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o751);
+
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
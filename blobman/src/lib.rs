@@ -14,12 +14,18 @@ expects.
 #![deny(missing_docs)]
 
 extern crate app_dirs;
+extern crate atty;
 extern crate bytes;
 #[macro_use] extern crate error_chain;
+extern crate filetime;
+extern crate fs2;
 extern crate futures;
 extern crate hyper;
+extern crate libc;
 extern crate mkstemp;
 extern crate native_tls;
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+extern crate openssl;
 #[macro_use] extern crate serde_derive;
 extern crate serde;
 extern crate sha2;
@@ -29,6 +35,7 @@ extern crate tokio_io;
 extern crate tokio_service;
 extern crate tokio_tls;
 extern crate toml;
+extern crate toml_edit;
 
 #[macro_use] pub mod notify; // must come first to provide macros for other modules
 #[macro_use] pub mod errors;
@@ -40,12 +47,15 @@ pub mod manifest;
 pub mod storage;
 
 
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::SystemTime;
 
-use errors::{Error, Result};
+use digest::{Digest, Shim};
+use errors::{Error, ErrorKind, Result};
 
 
 const APP_INFO: app_dirs::AppInfo = app_dirs::AppInfo {name: "blobman", author: "BlobmanProject"};
@@ -64,6 +74,16 @@ pub enum IngestMode {
     /// have different contents. If no blob of the same name already exists,
     /// ingest it.
     TrustExisting,
+
+    /// Ingest the new blob, but if a blob of the same name already exists
+    /// and the new blob has different contents, fail without altering
+    /// storage or the manifest.
+    Strict,
+
+    /// Obtain the new blob and check that it has the same contents as the
+    /// existing blob of the same name, without recording anything. It is an
+    /// error if no blob of the same name exists.
+    Verify,
 }
 
 impl IngestMode {
@@ -71,7 +91,7 @@ impl IngestMode {
     /// purpose of this function is to assist the CLI in parsing command-line
     /// arguments that map to IngestMode values.
     pub fn stringifications() -> &'static [&'static str] {
-        static S: &'static[&str] = &["update", "trust"];
+        static S: &'static[&str] = &["update", "trust", "strict", "verify"];
         S
     }
 }
@@ -84,6 +104,10 @@ impl FromStr for IngestMode {
             Ok(IngestMode::Update)
         } else if s == "trust" {
             Ok(IngestMode::TrustExisting)
+        } else if s == "strict" {
+            Ok(IngestMode::Strict)
+        } else if s == "verify" {
+            Ok(IngestMode::Verify)
         } else {
             err_msg!("unrecognized ingestion mode \"{}\"", s)
         }
@@ -91,11 +115,300 @@ impl FromStr for IngestMode {
 }
 
 
+/// A digest-computing wrapper around a staging handle.
+type StagingShim = Shim<Box<storage::Staging>>;
+
+
+/// What to do with a blob that is being ingested.
+enum IngestPlan {
+    /// Store the blob and record it in the manifest.
+    Record,
+
+    /// Store and record the blob, but only if it has the same contents as
+    /// the existing entry.
+    RecordIfSame(manifest::BlobInfo),
+
+    /// Compare the blob with the existing entry without storing or
+    /// recording anything.
+    Compare(manifest::BlobInfo),
+}
+
+impl IngestPlan {
+    /// Get a destination for the data of the blob being ingested.
+    fn start_staging(&self, storage: &mut storage::Storage) -> Result<StagingShim> {
+        let staging = match *self {
+            IngestPlan::Compare(_) => Box::new(DiscardStaging) as Box<storage::Staging>,
+            _ => storage.start_staging()?,
+        };
+
+        Ok(Shim::new(staging))
+    }
+
+    /// Deal with the data of the blob once they have all been staged.
+    ///
+    /// Depending on the plan, the data are committed to storage or merely
+    /// compared with the existing entry. Returns the information that should
+    /// be recorded in the manifest, if any.
+    fn settle<B: notify::NotificationBackend>(&self, name: &str, shim: StagingShim, size: u64, nbe: &mut B)
+                                      -> Result<Option<manifest::BlobInfo>>
+    {
+        let (staging, digest) = shim.finish();
+        let observed = manifest::BlobInfo::new(size, digest);
+
+        let binfo = match *self {
+            IngestPlan::Record => observed,
+            IngestPlan::RecordIfSame(ref expected) => {
+                ctry!(expected.check_contents(&observed);
+                      "strict-mode ingestion of \"{}\" failed; nothing was changed", name);
+                expected.clone()
+            },
+            IngestPlan::Compare(ref expected) => {
+                ctry!(expected.check_contents(&observed); "the contents of \"{}\" have changed", name);
+                bm_note!(nbe, "{}: contents match the manifest", name);
+                return Ok(None);
+            },
+        };
+
+        ctry!(staging.commit(&digest); "couldn\'t store the data for \"{}\"", name);
+        Ok(Some(binfo))
+    }
+}
+
+
+/// A staging handle that throws away everything written to it.
+///
+/// This is used when blob data are only needed to compute a digest.
+struct DiscardStaging;
+
+impl Write for DiscardStaging {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl storage::Staging for DiscardStaging {
+    fn commit(self: Box<Self>, _digest: &digest::DigestData) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+impl http::ResumableDest for StagingShim {
+    fn resume_point(&self) -> Option<(u64, Option<String>)> {
+        self.get_ref().partial().map(|p| (p.size, p.validator))
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        // The data that we already have need to be included in the digest.
+        // If this is a retry, some of them may have been included already.
+        self.reset();
+
+        if let Some(mut r) = self.get_mut().read_partial()? {
+            self.preload(&mut r)?;
+        }
+
+        Ok(())
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        self.reset();
+        self.get_mut().discard_partial()
+    }
+
+    fn set_validator(&mut self, validator: Option<&str>) -> Result<()> {
+        self.get_mut().set_validator(validator)
+    }
+
+    fn clone_file(&mut self, src: &mut File) -> Result<Option<u64>> {
+        if !self.get_mut().clone_file(src)? {
+            return Ok(None);
+        }
+
+        // The data didn't pass through the shim, so we have to feed them to
+        // the digest separately.
+        self.reset();
+        src.seek(SeekFrom::Start(0))?;
+        Ok(Some(self.preload(src)?))
+    }
+}
+
+
+/// A download destination that refuses to hold more data than the manifest
+/// says a blob contains.
+///
+/// Without this, a misbehaving server could keep sending data until we
+/// reached the end of the stream, only for the digest check to fail.
+struct SizeCapped<D> {
+    inner: D,
+    n_held: u64,
+    limit: u64,
+}
+
+impl<D> SizeCapped<D> {
+    fn new(inner: D, limit: u64) -> Self {
+        SizeCapped {
+            inner: inner,
+            n_held: 0,
+            limit: limit,
+        }
+    }
+
+    fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: http::ResumableDest> Write for SizeCapped<D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.n_held + buf.len() as u64 > self.limit {
+            // These can't be the data that we want, so don't try to resume
+            // from them next time.
+            let _ = self.inner.restart();
+            self.n_held = 0;
+
+            return Err(std::io::Error::new(std::io::ErrorKind::Other,
+                                           format!("received more than the expected {} bytes", self.limit)));
+        }
+
+        let n = self.inner.write(buf)?;
+        self.n_held += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<D: http::ResumableDest> http::ResumableDest for SizeCapped<D> {
+    fn resume_point(&self) -> Option<(u64, Option<String>)> {
+        self.inner.resume_point()
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.n_held = self.inner.resume_point().map(|(n, _)| n).unwrap_or(0);
+        self.inner.resume()
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        self.n_held = 0;
+        self.inner.restart()
+    }
+
+    fn set_validator(&mut self, validator: Option<&str>) -> Result<()> {
+        self.inner.set_validator(validator)
+    }
+
+    fn clone_file(&mut self, src: &mut File) -> Result<Option<u64>> {
+        let result = self.inner.clone_file(src)?;
+
+        if let Some(n) = result {
+            self.n_held = n;
+        }
+
+        Ok(result)
+    }
+}
+
+
+/// Compute the key identifying a resumable download of a blob.
+///
+/// The key depends on both the URL and the expected digest, so that partial
+/// data are never reused for a different resource or a different version
+/// of the blob.
+fn transfer_key(url: &str, expected: &digest::DigestData) -> digest::DigestData {
+    let mut dc = digest::create();
+    dc.input(url.as_bytes());
+    dc.input(b"\n");
+    dc.input(expected.to_string().as_bytes());
+    dc.into()
+}
+
+
+/// Get the canonical absolute path of a manifest, which might not exist yet.
+///
+/// This is how storage areas identify manifests.
+fn absolute_manifest_path(path: &Path) -> Result<PathBuf> {
+    if path.exists() {
+        return Ok(ctry!(path.canonicalize(); "couldn\'t canonicalize path {}", path.display()));
+    }
+
+    let dir = match path.parent() {
+        Some(d) if d.as_os_str().len() > 0 => d,
+        _ => Path::new("."),
+    };
+
+    let dir = ctry!(dir.canonicalize(); "couldn\'t canonicalize path {}", dir.display());
+
+    match path.file_name() {
+        Some(n) => Ok(dir.join(n)),
+        None => err_msg!("cannot determine the file name of the manifest {}", path.display()),
+    }
+}
+
+
+/// Derive a blob name from the final component of a URL's path.
+fn name_from_url(url: &str) -> Result<String> {
+    if http::file::is_file_url(url) {
+        let path = http::file::path_from_url(url)?;
+
+        return match path.file_name().and_then(|s| s.to_str()) {
+            Some(s) => Ok(s.to_owned()),
+            None => err_msg!("cannot extract a filename from the URL {}", url),
+        };
+    }
+
+    let parsed: hyper::Uri = url.parse()?;
+
+    match parsed.path().split("/").last() {
+        Some(s) if s.len() > 0 => Ok(s.to_owned()),
+        _ => err_msg!("cannot extract a filename from the URL {}", http::auth::redact_url(url)),
+    }
+}
+
+
+/// Copy data from *reader* into *writer*, reporting progress to *nbe*.
+///
+/// *what* and *total* are passed along to `progress_start()`. The number of
+/// bytes copied is returned.
+fn copy_with_progress<R, B>(reader: &mut R, writer: &mut Write, nbe: &mut B, what: &str,
+                            total: Option<u64>) -> Result<u64>
+    where R: Read + ?Sized, B: notify::NotificationBackend
+{
+    let mut buf = vec![0u8; 65536];
+    let mut n_bytes = 0;
+
+    nbe.progress_start(0, what, 0, total);
+
+    let result = (|| -> Result<u64> {
+        loop {
+            let n = reader.read(&mut buf)?;
+
+            if n == 0 {
+                return Ok(n_bytes);
+            }
+
+            writer.write_all(&buf[..n])?;
+            n_bytes += n as u64;
+            nbe.progress_update(0, n_bytes);
+        }
+    })();
+
+    nbe.progress_end(0);
+    result
+}
+
+
 /// A session in which we do stuff.
 pub struct Session<'a, B: 'a + notify::NotificationBackend> {
     config: &'a config::UserConfig,
     nbe: &'a mut B,
     manifest: manifest::Manifest,
+    manifest_base: manifest::Manifest,
     manifest_path: Option<PathBuf>,
     manifest_modified: bool,
 }
@@ -106,78 +419,373 @@ impl<'a, B: notify::NotificationBackend> Session<'a, B> {
     pub fn new(config: &'a config::UserConfig, nbe: &'a mut B) -> Result<Self> {
         let (manifest, manifest_path) = manifest::Manifest::find()?;
 
-        Ok(Self {
+        let mut sess = Self {
             config: config,
             nbe: nbe,
+            manifest_base: manifest.clone(),
             manifest: manifest,
             manifest_path: manifest_path,
             manifest_modified: false,
-        })
+        };
+
+        sess.register_manifest();
+        Ok(sess)
+    }
+
+
+    /// Record the path of our manifest file in the storage area's registry.
+    ///
+    /// This is how storage areas learn which manifests refer to them. Failure
+    /// to register is not fatal, since it only degrades our ability to do
+    /// things like garbage collection.
+    fn register_manifest(&mut self) {
+        if let Err(e) = self.try_register_manifest() {
+            bm_warning!(self.nbe, "couldn\'t register the manifest with the storage area"; e);
+        }
+    }
+
+    fn try_register_manifest(&mut self) -> Result<()> {
+        let path = match self.manifest_path {
+            Some(ref p) => absolute_manifest_path(p)?,
+            None => { return Ok(()); },
+        };
+
+        let mut storage = self.get_storage()?;
+        storage.register_manifest(&path)
+    }
+
+
+    /// Get the manifest of blobs known to this session.
+    pub fn manifest(&self) -> &manifest::Manifest {
+        &self.manifest
     }
 
 
     /// Get a storage backend for this session.
     ///
-    /// TODO: Maybe we'll one day have multiple backends and some fancy way to
-    /// decide which one to use. For now we just use one. We do, however, use
-    /// a trait object since we envision have runtime-configurable backends
-    /// here.
+    /// All of the configured storage areas are combined into one backend that
+    /// consults them in priority order. We use a trait object since the
+    /// backends are configured at runtime.
     pub fn get_storage(&mut self) -> Result<Box<storage::Storage>> {
         self.config.get_storage(self.nbe)
     }
 
     /// Fetch a blob from a URL and ingest it.
+    ///
+    /// If *name* is not specified, the blob is named after the final
+    /// component of the URL path.
     pub fn ingest_from_url(&mut self, mode: IngestMode, url: &str, name: Option<&str>) -> Result<()> {
-        let parsed: hyper::Uri = url.parse()?;
+        let file_name = match name {
+            Some(n) => n.to_owned(),
+            None => name_from_url(url)?,
+        };
+
+        let mut downloader = ctry!(self.config.get_downloader(self.nbe); "couldn\'t set up the downloader");
+        self.ingest(mode, &file_name, Some(url), |shim, nbe| downloader.download_cloning(url, shim, nbe))
+    }
+
+
+    /// Fetch blobs from several URLs and ingest them.
+    ///
+    /// The downloads run concurrently, up to the configured limit, and each
+    /// blob is named after the final component of its URL. Problems with
+    /// individual blobs are reported through the notification backend
+    /// rather than aborting the whole batch, and the number of URLs that
+    /// could not be ingested is returned.
+    pub fn ingest_from_urls(&mut self, mode: IngestMode, urls: &[&str]) -> Result<usize> {
+        let mut storage = ctry!(self.get_storage(); "cannot open storage backend");
+        let mut jobs = Vec::new();
+        let mut pending = Vec::new();
+        let mut n_bad = 0;
+
+        for &url in urls {
+            // Credentials embedded in the URL are used for the download, but
+            // never shown or recorded.
+            let public_url = http::auth::redact_url(url);
+
+            match self.start_url_ingest(mode, url) {
+                Ok(Some((name, plan))) => {
+                    jobs.push((url.to_owned(), pending.len()));
+                    pending.push((name, public_url, plan));
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    bm_severe!(self.nbe, "couldn\'t ingest {}", public_url; e);
+                    n_bad += 1;
+                },
+            }
+        }
+
+        if jobs.len() == 0 {
+            return Ok(n_bad);
+        }
+
+        let mut downloader = ctry!(self.config.get_downloader(self.nbe); "couldn\'t set up the downloader");
+
+        // Staging handles are only created as transfers start, and are
+        // committed as soon as they end, so that we don't hold one open for
+        // every URL in a big batch.
+        let results = downloader.download_many_cloning(jobs, |i, nbe: &mut B| {
+            let (ref name, ref public_url, ref plan) = pending[i];
+            let shim = ctry!(plan.start_staging(&mut *storage); "couldn\'t prepare storage for the download");
+            bm_note!(nbe, "{}: downloading from {}", name, public_url);
+            Ok(shim)
+        }, |i, result, nbe: &mut B| {
+            let (ref name, _, ref plan) = pending[i];
+            result.and_then(|(shim, size)| plan.settle(name, shim, size, nbe))
+        }, self.nbe);
+        let mut ingested = HashSet::new();
+
+        for ((name, url, _), result) in pending.into_iter().zip(results) {
+            match result {
+                Ok(Some(binfo)) => {
+                    ingested.insert(self.record_ingest(&name, Some(&url), binfo));
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    bm_severe!(self.nbe, "{}: failed to ingest from {}", name, url; e);
+                    n_bad += 1;
+                },
+            }
+        }
+
+        if ingested.len() > 0 {
+            self.enforce_budget(&mut *storage, &ingested);
+        }
+
+        Ok(n_bad)
+    }
+
+
+    /// Prepare to ingest a blob from a URL as part of a batch.
+    ///
+    /// Returns the name of the blob and the ingestion plan, or None if there
+    /// is nothing to do.
+    fn start_url_ingest(&self, mode: IngestMode, url: &str) -> Result<Option<(String, IngestPlan)>> {
+        let name = name_from_url(url)?;
+
+        Ok(match self.plan_ingest(mode, &name)? {
+            Some(p) => Some((name, p)),
+            None => None,
+        })
+    }
+
+
+    /// Ingest a blob from a file on the local filesystem.
+    ///
+    /// If *name* is not specified, the blob is named after the final
+    /// component of *path*.
+    pub fn ingest_from_path<P: AsRef<Path>>(&mut self, mode: IngestMode, path: P, name: Option<&str>) -> Result<()> {
+        let path = path.as_ref();
         let file_name = match name {
             Some(n) => n,
-            None => match parsed.path().split("/").last() {
-                None => { return err_msg!("cannot extract a filename from the URL {}", url); },
+            None => match path.file_name().and_then(|s| s.to_str()) {
+                None => { return err_msg!("cannot extract a filename from the path {}", path.display()); },
                 Some(s) => s,
             },
         };
 
+        let mut f = ctry!(File::open(path); "couldn\'t open {}", path.display());
+        let total = f.metadata().ok().map(|m| m.len());
+        let what = path.display().to_string();
+        self.ingest(mode, file_name, None, |w, nbe| copy_with_progress(&mut f, w, nbe, &what, total))
+    }
+
+
+    /// Ingest a blob from an arbitrary Read stream.
+    ///
+    /// Unlike the other ingestion functions, a name must always be provided
+    /// since there is nothing to derive it from.
+    pub fn ingest_from_reader<R: Read>(&mut self, mode: IngestMode, reader: &mut R, name: &str) -> Result<()> {
+        self.ingest(mode, name, None, |w, nbe| copy_with_progress(reader, w, nbe, name, None))
+    }
+
+
+    /// The common implementation of the various single-blob ingestion
+    /// functions.
+    fn ingest<F>(&mut self, mode: IngestMode, name: &str, url: Option<&str>, filler: F) -> Result<()>
+        where F: FnOnce(&mut StagingShim, &mut B) -> Result<u64>
+    {
+        let plan = match self.plan_ingest(mode, name)? {
+            Some(p) => p,
+            None => { return Ok(()); },
+        };
+
+        // If the filler fails, the staging handle is dropped, which discards
+        // whatever partial data were written to it.
         let mut storage = ctry!(self.get_storage(); "cannot open storage backend");
+        let mut shim = plan.start_staging(&mut *storage)?;
+        let size = filler(&mut shim, self.nbe)?;
+
+        if let Some(digest) = self.conclude_ingest(plan, name, url, shim, size)? {
+            let mut keep = HashSet::new();
+            keep.insert(digest);
+            self.enforce_budget(&mut *storage, &keep);
+        }
 
-        if let IngestMode::TrustExisting = mode {
-            if self.manifest.lookup(file_name).is_some() {
-                return Ok(());
+        Ok(())
+    }
+
+
+    /// Decide what to do with a blob that is about to be ingested.
+    ///
+    /// Returns None if the blob should not be ingested at all.
+    fn plan_ingest(&self, mode: IngestMode, name: &str) -> Result<Option<IngestPlan>> {
+        Ok(match (mode, self.manifest.lookup(name)) {
+            (IngestMode::TrustExisting, Some(_)) => None,
+            (IngestMode::Verify, None) => {
+                return err_msg!("cannot verify \"{}\": no blob of that name is registered", name);
+            },
+            (IngestMode::Verify, Some(expected)) => Some(IngestPlan::Compare(expected.clone())),
+            (IngestMode::Strict, Some(expected)) => Some(IngestPlan::RecordIfSame(expected.clone())),
+            _ => Some(IngestPlan::Record),
+        })
+    }
+
+
+    /// Finish ingesting a blob once all of its data have been obtained.
+    ///
+    /// Depending on the plan, the data are committed to storage and
+    /// recorded in the manifest, or merely compared with the existing
+    /// entry. Returns the digest of the blob if it was recorded.
+    fn conclude_ingest(&mut self, plan: IngestPlan, name: &str, url: Option<&str>, shim: StagingShim,
+                       size: u64) -> Result<Option<digest::DigestData>>
+    {
+        Ok(match plan.settle(name, shim, size, self.nbe)? {
+            Some(binfo) => Some(self.record_ingest(name, url, binfo)),
+            None => None,
+        })
+    }
+
+
+    /// Record a newly-ingested blob in the manifest.
+    ///
+    /// Returns the digest of the blob.
+    fn record_ingest(&mut self, name: &str, url: Option<&str>, mut binfo: manifest::BlobInfo)
+                     -> digest::DigestData
+    {
+        if let Some(u) = url {
+            binfo.set_url(u);
+        }
+
+        // If the data haven't changed, the places we knew to get them from
+        // are still good.
+        if let Some(old) = self.manifest.lookup(name) {
+            if old.same_contents(&binfo) {
+                for u in old.urls() {
+                    binfo.add_url(u);
+                }
             }
         }
 
-        let mut binfo = manifest::BlobInfo::new_from_ingest(|w| http::download(url, w), &mut *storage)?;
-        binfo.set_url(url);
-        self.manifest.insert_or_update(file_name, binfo, self.nbe);
+        let digest = *binfo.digest();
+        self.manifest.insert_or_update(name, binfo, self.nbe);
         self.manifest_modified = true;
+        digest
+    }
 
-        Ok(())
+
+    /// Evict blobs from storage if it has grown beyond its size budget.
+    ///
+    /// Failure to evict is reported but not fatal, since the blobs that we
+    /// care about right now are still there.
+    fn enforce_budget(&mut self, storage: &mut storage::Storage, keep: &HashSet<digest::DigestData>) {
+        match storage.enforce_budget(keep) {
+            Ok(evicted) => {
+                for blob in evicted {
+                    bm_note!(self.nbe, "evicted {} ({} bytes) to stay within the storage size limit",
+                             blob.path.display(), blob.size);
+                }
+            },
+            Err(e) => {
+                bm_warning!(self.nbe, "couldn\'t evict blobs to stay within the storage size limit"; e);
+            },
+        }
+    }
+
+
+    /// Pin or unpin the named blob in storage.
+    ///
+    /// Pinned blobs are never evicted to keep the storage area within its
+    /// size budget.
+    pub fn pin_blob(&mut self, name: &str, pinned: bool) -> Result<()> {
+        let mut storage = ctry!(self.get_storage(); "cannot open storage backend");
+
+        let binfo = match self.manifest.lookup(name) {
+            Some(b) => b,
+            None => { return err_msg!("no known blob named \"{}\"", name); },
+        };
+
+        storage.set_pinned(binfo.digest(), pinned)
     }
 
 
     /// Rewrite the manifest if needed.
+    ///
+    /// Other processes may have modified the manifest file since we loaded
+    /// it. To avoid losing their work, we lock the manifest, and if it has
+    /// changed, we merge our changes into the new version before writing it
+    /// out. The lock is taken on a file next to the manifest, named after it
+    /// with `.lock` appended, which is deleted again once we're done.
     pub fn rewrite_manifest(&mut self) -> Result<()> {
         if !self.manifest_modified {
             return Ok(());
         }
 
-        let path = self.manifest_path
-            .as_ref()
-            .map(|pb| pb.as_ref())
-            .unwrap_or_else(|| Path::new(manifest::MANIFEST_STEM));
-        let text = toml::ser::to_string_pretty(&self.manifest)?;
-        let mut f = File::create(&path)?;
-        ctry!(write!(f, "{}", text); "couldn\'t write manifest file {}", path.display());
+        if self.manifest_path.is_none() {
+            self.manifest_path = Some(PathBuf::from(manifest::MANIFEST_STEM));
+        }
+
+        {
+            let path = self.manifest_path.clone().unwrap();
+            let mut lock_path = path.clone().into_os_string();
+            lock_path.push(".lock");
+
+            let _lock = match io::lock_transient(&lock_path) {
+                Ok(l) => Some(l),
+                Err(e) => {
+                    bm_warning!(self.nbe, "couldn\'t lock manifest {}; changes made by other processes \
+                                           at the same time may be lost", path.display(); e);
+                    None
+                },
+            };
+
+            let original = match ctry!(io::try_open(&path); "couldn\'t open manifest {}", path.display()) {
+                Some(mut f) => {
+                    let mut text = String::new();
+                    ctry!(f.read_to_string(&mut text); "couldn\'t read manifest {}", path.display());
+                    Some(text)
+                },
+                None => None,
+            };
+
+            if let Some(ref text) = original {
+                let on_disk: manifest::Manifest = ctry!(toml::from_str(text);
+                                                        "couldn\'t parse manifest {}", path.display());
+
+                if on_disk != self.manifest_base {
+                    bm_note!(self.nbe, "{} was modified by another process; merging changes", path.display());
+                    self.manifest = self.manifest.merge(&self.manifest_base, &on_disk, self.nbe);
+                }
+            }
+
+            // We apply our changes to the existing document so that comments
+            // and formatting are preserved.
+            let text = ctry!(self.manifest.to_toml_string(original.as_ref().map(|s| s.as_ref()));
+                             "couldn\'t update manifest {}", path.display());
+            ctry!(io::atomic_write(&path, text.as_bytes()); "couldn\'t write manifest file {}", path.display());
+        }
+
+        self.manifest_base = self.manifest.clone();
         self.manifest_modified = false;
+        self.register_manifest();
         Ok(())
     }
 
 
     /// Provide a blob in the current directory.
-    ///
-    /// We should eventually have some method to identify which of several
-    /// Storage backends has the blob we want, but for now there's just one.
     pub fn provide_blob(&mut self, name: &str) -> Result<()> {
-        let storage = ctry!(self.get_storage(); "cannot open storage backend");
+        let mut storage = ctry!(self.get_storage(); "cannot open storage backend");
 
         let storage_path = {
             let binfo = match self.manifest.lookup(name) {
@@ -185,6 +793,14 @@ impl<'a, B: notify::NotificationBackend> Session<'a, B> {
                 None => { return err_msg!("no known blob named \"{}\"", name); },
             };
 
+            if let storage::Availability::Missing = binfo.availability(&*storage)? {
+                if self.config.is_offline() {
+                    return Err(ErrorKind::Offline(format!("the missing blob \"{}\"", name)).into());
+                }
+
+                return err_msg!("blob \"{}\" is missing from storage; try \"blobman sync\"", name);
+            }
+
             match storage.get_path(binfo.digest())? {
                 Some(p) => p,
                 None => { return err_msg!("blob \"{}\" not available as standalone file", name); },
@@ -202,9 +818,289 @@ impl<'a, B: notify::NotificationBackend> Session<'a, B> {
     }
 
 
+    /// Make every blob in the manifest available in storage.
+    ///
+    /// Blobs that are missing from storage are downloaded from their recorded
+    /// URLs, several at a time. Downloads that were interrupted on an
+    /// earlier run are resumed where possible. Each download is checked
+    /// against the size and digest recorded in the manifest before it is
+    /// committed to storage; if it fails either way, the blob's mirrors are
+    /// tried in turn. Blobs with identical contents are downloaded only
+    /// once. In offline mode, only `file://` URLs are used, and
+    /// blobs that can't be obtained that way are reported. Problems are
+    /// reported through the notification backend, and the number of blobs
+    /// that could not be made available is returned.
+    pub fn sync(&mut self) -> Result<usize> {
+        let mut storage = ctry!(self.get_storage(); "cannot open storage backend");
+        let mut todo: Vec<(Vec<&str>, &manifest::BlobInfo, Vec<&str>)> = Vec::new();
+        let mut n_bad = 0;
+        let offline = self.config.is_offline();
+        let mut missing: Vec<(&str, &manifest::BlobInfo)> = Vec::new();
+
+        for (name, binfo) in self.manifest.iter() {
+            if let storage::Availability::Missing = binfo.availability(&*storage)? {
+                missing.push((name, binfo));
+            }
+        }
+
+        missing.sort_by_key(|&(name, _)| name);
+
+        // Blobs with the same contents only need to be downloaded once, but
+        // any of their URLs will do.
+        let mut by_contents = HashMap::new();
+        let mut order = Vec::new();
+        let mut downloader = None;
+
+        for (name, binfo) in missing {
+            let key = (binfo.size(), binfo.digest());
+            let entry = by_contents.entry(key).or_insert_with(|| {
+                order.push(key);
+                (Vec::new(), binfo, Vec::new())
+            });
+
+            entry.0.push(name);
+
+            for url in binfo.urls() {
+                if !entry.2.contains(&url) {
+                    entry.2.push(url);
+                }
+            }
+        }
+
+        for key in order {
+            let (names, binfo, mut urls) = by_contents.remove(&key).unwrap();
+
+            if urls.len() == 0 {
+                bm_severe!(self.nbe, "{}: missing from storage, and no URL is known for it", names.join(", "));
+                n_bad += names.len();
+                continue;
+            }
+
+            if offline {
+                // Rewrite rules may point remote URLs at local mirrors, so
+                // let the downloader decide which ones it can use.
+                if downloader.is_none() {
+                    downloader = Some(ctry!(self.config.get_downloader(self.nbe); "couldn\'t set up the downloader"));
+                }
+
+                let dl = downloader.as_ref().unwrap();
+                urls.retain(|u| dl.is_allowed(u));
+
+                if urls.len() == 0 {
+                    bm_severe!(self.nbe, "{}: missing from storage, and cannot be downloaded in offline mode",
+                               names.join(", "));
+                    n_bad += names.len();
+                    continue;
+                }
+            }
+
+            todo.push((names, binfo, urls));
+        }
+
+        // Each round tries the next untried URL of every blob that we
+        // haven't managed to get yet.
+        let mut fetched = HashSet::new();
+        let mut round = 0;
+
+        while todo.len() > 0 {
+            let jobs: Vec<_> = todo.iter()
+                .enumerate()
+                .map(|(i, &(_, _, ref urls))| (urls[round].to_owned(), i))
+                .collect();
+
+            if downloader.is_none() {
+                downloader = Some(ctry!(self.config.get_downloader(self.nbe); "couldn\'t set up the downloader"));
+            }
+
+            // Staging handles lock their partial files, so they're only
+            // created as transfers start and are dealt with as soon as they
+            // end. Nothing is committed unless it has exactly the size and
+            // digest that the manifest expects.
+            let results = downloader.as_mut().unwrap().download_many_resumable(jobs, |i, nbe: &mut B| {
+                let (ref names, binfo, ref urls) = todo[i];
+                let url = urls[round];
+                let staging = ctry!(storage.start_resumable_staging(&transfer_key(url, binfo.digest()));
+                                    "couldn\'t prepare storage for the download");
+
+                match staging.partial() {
+                    Some(p) => bm_note!(nbe, "{}: resuming download from {} after {} bytes",
+                                        names.join(", "), http::auth::redact_url(url), p.size),
+                    None => bm_note!(nbe, "{}: downloading from {}", names.join(", "), http::auth::redact_url(url)),
+                }
+
+                Ok(SizeCapped::new(Shim::new(staging), binfo.size()))
+            }, |i, result, _| {
+                let binfo = todo[i].1;
+
+                result.and_then(|(capped, size)| {
+                    let (mut staging, digest) = capped.into_inner().finish();
+
+                    if let Err(e) = binfo.check_contents(&manifest::BlobInfo::new(size, digest)) {
+                        // Don't try to resume from bad data next time.
+                        let _ = staging.discard_partial();
+                        return Err(e);
+                    }
+
+                    staging.commit(&digest)
+                })
+            }, self.nbe);
+            let pending = todo;
+            todo = Vec::new();
+
+            for ((names, binfo, urls), outcome) in pending.into_iter().zip(results) {
+                let name = names.join(", ");
+
+                match outcome {
+                    Ok(_) => {
+                        fetched.insert(*binfo.digest());
+                    },
+                    Err(e) => {
+                        if round + 1 < urls.len() {
+                            bm_warning!(self.nbe, "{}: failed to download from {}; trying the next mirror",
+                                        name, http::auth::redact_url(urls[round]); e);
+                            todo.push((names, binfo, urls));
+                        } else {
+                            bm_severe!(self.nbe, "{}: failed to download from {}", name,
+                                       http::auth::redact_url(urls[round]); e);
+                            n_bad += names.len();
+                        }
+                    },
+                }
+            }
+
+            round += 1;
+        }
+
+        if fetched.len() > 0 {
+            self.enforce_budget(&mut *storage, &fetched);
+        }
+
+        Ok(n_bad)
+    }
+
+
+    /// Record an additional URL from which the named blob can be obtained.
+    ///
+    /// If the blob's main URL stops working, `sync()` tries its mirrors in
+    /// the order in which they were added. The URL is not contacted here;
+    /// data obtained from it are checked against the manifest when they are
+    /// downloaded.
+    pub fn add_mirror(&mut self, name: &str, url: &str) -> Result<()> {
+        let mut binfo = match self.manifest.lookup(name) {
+            Some(b) => b.clone(),
+            None => { return err_msg!("no known blob named \"{}\"", name); },
+        };
+
+        if !binfo.add_url(url) {
+            bm_note!(self.nbe, "{} is already a known source of {}", http::auth::redact_url(url), name);
+            return Ok(());
+        }
+
+        self.manifest.insert_or_update(name, binfo, self.nbe);
+        self.manifest_modified = true;
+        Ok(())
+    }
+
+
+    /// Check that stored blobs still match their manifest entries.
+    ///
+    /// If *names* is empty, every blob in the manifest is checked. Problems
+    /// are reported through the notification backend, and the number of
+    /// blobs that are missing or corrupt is returned.
+    pub fn verify_blobs(&mut self, names: &[&str]) -> Result<usize> {
+        let mut storage = ctry!(self.get_storage(); "cannot open storage backend");
+
+        let mut to_check: Vec<(&str, &manifest::BlobInfo)> = if names.len() == 0 {
+            self.manifest.iter().collect()
+        } else {
+            let mut v = Vec::new();
+
+            for name in names {
+                match self.manifest.lookup(name) {
+                    Some(b) => v.push((*name, b)),
+                    None => { return err_msg!("no known blob named \"{}\"", name); },
+                }
+            }
+
+            v
+        };
+
+        to_check.sort_by_key(|&(name, _)| name);
+        let mut n_bad = 0;
+
+        for (name, binfo) in to_check {
+            match ctry!(binfo.verify(&mut *storage); "error while verifying blob \"{}\"", name) {
+                manifest::Verification::Valid => {
+                    bm_note!(self.nbe, "{}: OK", name);
+                },
+                manifest::Verification::Missing => {
+                    bm_severe!(self.nbe, "{}: missing from storage", name);
+                    n_bad += 1;
+                },
+                manifest::Verification::Corrupt(size, digest) => {
+                    bm_severe!(self.nbe, "{}: corrupt: expected {} bytes with SHA256 {}; found {} bytes with SHA256 {}",
+                               name, binfo.size(), binfo.digest().to_string(), size, digest.to_string());
+                    n_bad += 1;
+                },
+            }
+        }
+
+        Ok(n_bad)
+    }
+
+
+    /// Remove blobs from storage that are not referenced by any known
+    /// manifest.
+    ///
+    /// The known manifests are this session's manifest and all of the
+    /// manifests recorded in the storage area's registry. Note that a
+    /// manifest only gets registered once a session has been run with it, so
+    /// blobs used by projects that haven't been touched in a while may be
+    /// considered garbage.
+    ///
+    /// If *dry_run* is true, nothing is deleted, but the returned report
+    /// describes what would have been done. The actions taken are also
+    /// reported through the notification backend.
+    pub fn gc(&mut self, dry_run: bool) -> Result<storage::GcReport> {
+        // Blobs stored from now on might belong to manifests that we've
+        // already looked at, so they have to be left alone.
+        let started = SystemTime::now();
+        let mut storage = ctry!(self.get_storage(); "cannot open storage backend");
+        let mut keep: HashSet<_> = self.manifest.iter().map(|(_, binfo)| *binfo.digest()).collect();
+
+        for path in ctry!(storage.registered_manifests(); "couldn\'t read the manifest registry") {
+            // If we can't read a registered manifest, we can't know which
+            // blobs it needs, so it's not safe to continue.
+            let other = ctry!(manifest::Manifest::load(&path);
+                              "refusing to garbage-collect storage: registered manifest {} is unreadable",
+                              path.display());
+            keep.extend(other.iter().map(|(_, binfo)| *binfo.digest()));
+        }
+
+        let report = ctry!(storage.gc(&keep, started, dry_run); "error while garbage-collecting storage");
+        let verb = if dry_run { "would remove" } else { "removed" };
+
+        for blob in &report.removed {
+            bm_note!(self.nbe, "{} {} ({} bytes)", verb, blob.path.display(), blob.size);
+        }
+
+        for path in &report.stale_staging {
+            bm_note!(self.nbe, "{} abandoned staging file {}", verb, path.display());
+        }
+
+        for blob in &report.linked {
+            bm_warning!(self.nbe, "not removing {}: it is unreferenced, but {} other hard link(s) to it exist",
+                        blob.path.display(), blob.n_links - 1);
+        }
+
+        bm_note!(self.nbe, "{} {} blob(s) totaling {} bytes", verb, report.removed.len(), report.bytes_freed());
+        Ok(report)
+    }
+
+
     /// Get a Read stream to the named blob.
     pub fn open_blob(&mut self, name: &str) -> Result<Box<Read>> {
-        let storage = ctry!(self.get_storage(); "cannot open storage backend");
+        let mut storage = ctry!(self.get_storage(); "cannot open storage backend");
 
         let binfo = match self.manifest.lookup(name) {
             Some(b) => b,
@@ -7,19 +7,23 @@ Handling of the manifest of known blobs.
 */
 
 use serde::{Serialize, Serializer};
-use std::collections::BTreeMap;
-use std::collections::hash_map::{Entry, HashMap};
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::hash_map::{self, Entry, HashMap};
+use std::fs::File;
 use std::io as std_io;
 use std::io::Read;
-use std::path::{Component, PathBuf};
+use std::iter::FromIterator;
+use std::path::{Component, Path, PathBuf};
 use std::result::Result as StdResult;
 use toml;
+use toml_edit::{self, Decor, Document, Item, TableLike, Value};
 
 use digest::{DigestData, Shim};
 use errors::Result;
+use http;
 use io;
 use notify::NotificationBackend;
-use storage::Storage;
+use storage::{Availability, Storage};
 
 
 /// The basename used by manifest files.
@@ -27,16 +31,49 @@ pub const MANIFEST_STEM: &'static str = ".blobs.toml";
 const PARENT_DIR: &'static str = "..";
 
 
+/// The result of checking a stored blob against the information recorded
+/// about it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Verification {
+    /// The stored data match the expected size and digest.
+    Valid,
+
+    /// The blob could not be found in storage.
+    Missing,
+
+    /// The stored data do not match. The size and digest of the data that
+    /// were actually found are included.
+    Corrupt(u64, DigestData),
+}
+
+
 /// Information about a blob.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct BlobInfo {
     size: u64,
     sha256: DigestData,
     url: Option<String>,
+
+    /// Other URLs from which the blob can be obtained, to be tried in order
+    /// if the main one fails.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mirrors: Vec<String>,
 }
 
 
 impl BlobInfo {
+    /// Create a BlobInfo describing data of the specified size and digest.
+    ///
+    /// No URL is associated with the new object.
+    pub fn new(size: u64, digest: DigestData) -> Self {
+        Self {
+            size: size,
+            sha256: digest,
+            url: None,
+            mirrors: Vec::new(),
+        }
+    }
+
     /// Ingest a new blob and extract its properties.
     ///
     /// The newly-ingested blob is staged in the storage area *storage*. The
@@ -46,39 +83,168 @@ impl BlobInfo {
     ///
     /// The somewhat awkward architecture here is because of how we have to
     /// interface with the async, futures-based hyper HTTP library.
+    ///
+    /// This is no longer used by the `Session` ingestion functions, which
+    /// need to check the data against the manifest before committing them
+    /// and to stage them while the filler is not in control.
+    #[deprecated(note = "use the ingestion functions of `Session`, which check the data against the manifest")]
     pub fn new_from_ingest<F>(filler: F, storage: &mut Storage) -> Result<Self>
-        where F: FnOnce(&mut Shim<Box<std_io::Write>>) -> Result<u64>
+        where F: FnOnce(&mut std_io::Write) -> Result<u64>
     {
-        let (cookie, digest, size) = {
-            let (sink, cookie) = storage.start_staging()?;
-            let mut shim = Shim::new(sink);
-            let size = filler(&mut shim)?;
-            let (_sink, digest) = shim.finish();
-            (cookie, digest, size)
-        };
-        storage.finish_staging(cookie, &digest)?;
+        // If the filler fails, the staging handle is dropped, which discards
+        // whatever partial data were written to it.
+        let mut shim = Shim::new(storage.start_staging()?);
+        let size = filler(&mut shim)?;
+        let (staging, digest) = shim.finish();
+        staging.commit(&digest)?;
 
         Ok(Self {
             size: size,
             sha256: digest,
             url: None,
+            mirrors: Vec::new(),
         })
     }
 
+    /// Determine whether two BlobInfos describe the same data.
+    ///
+    /// Unlike the equality operator, this ignores metadata such as the URL.
+    pub fn same_contents(&self, other: &BlobInfo) -> bool {
+        self.size == other.size && self.sha256 == other.sha256
+    }
+
+    /// Check that some data have the contents described by this BlobInfo.
+    ///
+    /// *observed* describes the data that were actually obtained. If its
+    /// size or digest differ from ours, an error describing the discrepancy
+    /// is returned.
+    pub fn check_contents(&self, observed: &BlobInfo) -> Result<()> {
+        if !self.same_contents(observed) {
+            return err_msg!("expected {} bytes with SHA256 {}; got {} bytes with SHA256 {}",
+                            self.size, self.sha256.to_string(), observed.size, observed.sha256.to_string());
+        }
+
+        Ok(())
+    }
+
     /// Get the digest associated with this blob.
     pub fn digest<'a>(&'a self) -> &'a DigestData {
         &self.sha256
     }
 
+    /// Get the size of this blob, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the URL associated with this blob, if there is one.
+    pub fn url<'a>(&'a self) -> Option<&'a str> {
+        self.url.as_ref().map(|s| s.as_ref())
+    }
+
+    /// Get every URL from which this blob can be obtained, in the order in
+    /// which they should be tried.
+    ///
+    /// The main URL comes first, followed by any mirrors.
+    pub fn urls<'a>(&'a self) -> Vec<&'a str> {
+        self.url.iter().chain(self.mirrors.iter()).map(|s| s.as_ref()).collect()
+    }
+
+    /// Write our information into a TOML table.
+    ///
+    /// Existing values are replaced in-place so that their formatting and any
+    /// associated comments are preserved. Fields that we don't know about are
+    /// left alone.
+    fn update_table(&self, table: &mut TableLike) {
+        set_value_preserving_decor(table, "size", Value::from(self.size as i64));
+        set_value_preserving_decor(table, "sha256", Value::from(self.sha256.to_string()));
+
+        match self.url {
+            Some(ref u) => { set_value_preserving_decor(table, "url", Value::from(u.as_ref() as &str)); },
+            None => { table.remove("url"); },
+        }
+
+        if self.mirrors.len() > 0 {
+            let mirrors = Value::from_iter(self.mirrors.iter().map(|s| s.as_ref() as &str));
+            set_value_preserving_decor(table, "mirrors", mirrors);
+        } else {
+            table.remove("mirrors");
+        }
+    }
+
+    /// Determine whether this blob can be found in the storage area *storage*.
+    ///
+    /// This does not count as a use of the blob.
+    pub fn availability(&self, storage: &Storage) -> Result<Availability> {
+        storage.locate(&self.sha256)
+    }
+
+    /// Check that the copy of this blob in *storage* has the expected
+    /// contents.
+    ///
+    /// The stored data are streamed through the digest computer in their
+    /// entirety, so this may take a while for large blobs. If possible, the
+    /// data are read directly from the filesystem so that the check does not
+    /// count as a use of the blob.
+    pub fn verify(&self, storage: &mut Storage) -> Result<Verification> {
+        let mut stream = match storage.locate(&self.sha256)? {
+            Availability::File(p) => match io::try_open(&p)? {
+                Some(f) => Box::new(f) as Box<Read>,
+                None => { return Ok(Verification::Missing); },
+            },
+            Availability::Stream => match storage.open(&self.sha256)? {
+                Some(s) => s,
+                None => { return Ok(Verification::Missing); },
+            },
+            Availability::Missing => { return Ok(Verification::Missing); },
+        };
+
+        let mut shim = Shim::new(std_io::sink());
+        let size = std_io::copy(&mut stream, &mut shim)?;
+        let (_sink, digest) = shim.finish();
+
+        if size == self.size && digest == self.sha256 {
+            Ok(Verification::Valid)
+        } else {
+            Ok(Verification::Corrupt(size, digest))
+        }
+    }
+
     /// Set the URL associated with this object.
+    ///
+    /// Any credentials embedded in the URL are dropped, since the manifest
+    /// is meant to be shared.
     pub fn set_url(&mut self, url: &str) {
-        self.url = Some(url.to_owned());
+        let url = http::auth::redact_url(url);
+        self.mirrors.retain(|m| *m != url);
+        self.url = Some(url);
+    }
+
+    /// Add a URL from which this blob can be obtained.
+    ///
+    /// If the blob already has a main URL, the new one becomes its last
+    /// mirror. As with `set_url()`, embedded credentials are dropped.
+    /// Returns false if the URL was already known.
+    pub fn add_url(&mut self, url: &str) -> bool {
+        let url = http::auth::redact_url(url);
+
+        if self.urls().contains(&url.as_ref()) {
+            return false;
+        }
+
+        if self.url.is_none() {
+            self.url = Some(url);
+        } else {
+            self.mirrors.push(url);
+        }
+
+        true
     }
 }
 
 
 /// A table of known blobs.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Manifest {
     #[serde(serialize_with = "serialize_map_sorted")]
     blobs: HashMap<String,BlobInfo>,
@@ -95,6 +261,21 @@ fn serialize_map_sorted<S>(value: &HashMap<String, BlobInfo>, serializer: S) ->
 
 
 impl Manifest {
+    /// Load a manifest from the file at the specified path.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let f = ctry!(File::open(path); "couldn't open manifest {}", path.display());
+        Ok(ctry!(Self::from_file(f); "error loading manifest {}", path.display()))
+    }
+
+
+    fn from_file(mut f: File) -> Result<Self> {
+        let mut buf = Vec::<u8>::new();
+        f.read_to_end(&mut buf)?;
+        Ok(toml::from_slice(&buf)?)
+    }
+
+
     /// Locate manifest data on the filesystem and load them.
     ///
     /// We first try to load `.blobs.toml`. If that does not exist, we then
@@ -105,11 +286,9 @@ impl Manifest {
         let mut p = PathBuf::from(MANIFEST_STEM);
 
         loop {
-            if let Some(mut f) = ctry!(io::try_open(&p); "error trying to read {}", p.display()) {
+            if let Some(f) = ctry!(io::try_open(&p); "error trying to read {}", p.display()) {
                 // OK, we've got our hands on a manifest file.
-                let mut buf = Vec::<u8>::new();
-                f.read_to_end(&mut buf)?;
-                let manifest = toml::from_slice(&buf)?;
+                let manifest = ctry!(Self::from_file(f); "error loading manifest {}", p.display());
                 return Ok((manifest, Some(p)));
             }
 
@@ -149,12 +328,154 @@ impl Manifest {
     }
 
 
+    /// Iterate over the blobs known to this manifest.
+    ///
+    /// The iterator yields pairs of blob names and information. The ordering
+    /// of the items is arbitrary.
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter(self.blobs.iter())
+    }
+
+
     /// Look up information for the named blob.
     pub fn lookup<'a>(&'a self, name: &str) -> Option<&'a BlobInfo> {
         self.blobs.get(name)
     }
 
 
+    /// Merge our changes into a manifest that has been modified by someone
+    /// else.
+    ///
+    /// Here *self* is our version of the manifest, *base* is the version that
+    /// we started from, and *theirs* is the other modified version. Every
+    /// entry that we added, changed, or removed relative to *base* is applied
+    /// to *theirs*, and the result is returned. If both sides changed the
+    /// same entry, our version wins, except that if both versions describe
+    /// the same data, the URLs from theirs are kept as mirrors.
+    pub fn merge<B: NotificationBackend>(&self, base: &Manifest, theirs: &Manifest, nbe: &mut B) -> Manifest {
+        let mut merged = theirs.clone();
+
+        let names: BTreeSet<&String> = self.blobs.keys().chain(base.blobs.keys()).collect();
+
+        for name in names {
+            let ours = self.blobs.get(name);
+            let orig = base.blobs.get(name);
+
+            if ours == orig {
+                continue; // we didn't change this entry
+            }
+
+            let other = theirs.blobs.get(name);
+
+            if other != orig && other != ours {
+                if let (Some(o), Some(t)) = (ours, other) {
+                    if o.same_contents(t) {
+                        let mut binfo = o.clone();
+
+                        for u in t.urls() {
+                            binfo.add_url(u);
+                        }
+
+                        merged.blobs.insert(name.clone(), binfo);
+                        continue;
+                    }
+                }
+
+                bm_warning!(nbe, "the entry for {} was also changed by another process; keeping our version", name);
+            }
+
+            match ours {
+                Some(binfo) => { merged.blobs.insert(name.clone(), binfo.clone()); },
+                None => { merged.blobs.remove(name); },
+            }
+        }
+
+        merged
+    }
+
+
+    /// Serialize this manifest into TOML text.
+    ///
+    /// If *original* is provided, it should be the current text of the
+    /// manifest file. Rather than generating a fresh document, we apply our
+    /// entries to it, so that comments, ordering, and fields that blobman
+    /// does not know about survive. Entries that are not in this manifest are
+    /// removed from the document, and new entries are appended to it in
+    /// alphabetical order.
+    pub fn to_toml_string(&self, original: Option<&str>) -> Result<String> {
+        let text = match original {
+            Some(t) => t,
+            None => { return Ok(toml::ser::to_string_pretty(self)?); },
+        };
+
+        let mut doc: Document = text.parse()?;
+        let mut orphaned = String::new();
+
+        {
+            let root = doc.as_table_mut();
+
+            if !root.contains_key("blobs") {
+                let mut t = toml_edit::Table::new();
+                t.set_implicit(true);
+                root.insert("blobs", Item::Table(t));
+            }
+
+            let blobs = match root.get_mut("blobs").and_then(|i| i.as_table_like_mut()) {
+                Some(t) => t,
+                None => { return err_msg!("the \"blobs\" item in the manifest is not a table"); },
+            };
+
+            let stale: Vec<String> = blobs.iter()
+                .map(|(k, _)| k.to_owned())
+                .filter(|k| !self.blobs.contains_key(k))
+                .collect();
+
+            // Comments that are set apart from a removed entry by a blank
+            // line probably describe more than just that entry -- such as the
+            // whole file -- so they're kept with whatever comes next.
+            for name in stale {
+                let (position, kept) = match blobs.remove(&name) {
+                    Some(Item::Table(t)) => match (t.position(), detached_comments(t.decor())) {
+                        (Some(p), Some(c)) => (p, c),
+                        _ => { continue; },
+                    },
+                    _ => { continue; },
+                };
+
+                let next = blobs.iter_mut()
+                    .filter_map(|(_, i)| i.as_table_mut())
+                    .filter(|t| t.position().map(|p| p > position).unwrap_or(false))
+                    .min_by_key(|t| t.position());
+
+                match next {
+                    Some(t) => {
+                        let prefix = format!("{}{}", kept, t.decor().prefix().and_then(|p| p.as_str()).unwrap_or(""));
+                        t.decor_mut().set_prefix(prefix);
+                    },
+                    None => { orphaned.push_str(&kept); },
+                }
+            }
+
+            let ordered: BTreeMap<_, _> = self.blobs.iter().collect();
+
+            for (name, binfo) in ordered {
+                if !blobs.get(name).map(|i| i.is_table_like()).unwrap_or(false) {
+                    blobs.insert(name, toml_edit::table());
+                }
+
+                binfo.update_table(blobs.get_mut(name).and_then(|i| i.as_table_like_mut()).unwrap());
+            }
+        }
+
+        if orphaned.len() > 0 {
+            let trailing = format!("{}{}", doc.trailing().as_str().unwrap_or(""), orphaned);
+            doc.set_trailing(trailing);
+        }
+
+        Ok(doc.to_string())
+    }
+
+
     /// Register a new blob with the manifest.
     ///
     /// If a blob under the same name was already known, the old information
@@ -177,3 +498,173 @@ impl Manifest {
         }
     }
 }
+
+
+/// Set a value in a TOML table, keeping the decorations (whitespace and
+/// comments) of any existing value.
+fn set_value_preserving_decor(table: &mut TableLike, key: &str, mut value: Value) {
+    if let Some(existing) = table.get_mut(key).and_then(|i| i.as_value_mut()) {
+        *value.decor_mut() = existing.decor().clone();
+        *existing = value;
+        return;
+    }
+
+    table.insert(key, Item::Value(value));
+}
+
+
+/// Get the comments at the start of a TOML table's prefix that are
+/// separated from the table by a blank line, if there are any.
+fn detached_comments(decor: &Decor) -> Option<String> {
+    let prefix = decor.prefix().and_then(|p| p.as_str()).unwrap_or("");
+
+    match prefix.rfind("\n\n") {
+        Some(i) if prefix[..i].contains('#') => Some(format!("{}\n\n", prefix[..i].trim_right_matches('\n'))),
+        _ => None,
+    }
+}
+
+
+/// An iterator over the blobs in a Manifest.
+///
+/// This type is returned by `Manifest::iter()`.
+pub struct Iter<'a>(hash_map::Iter<'a, String, BlobInfo>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a BlobInfo);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(k, v)| (k.as_ref(), v))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::NoopNotificationBackend;
+    use toml;
+
+    fn digest(byte: &str) -> DigestData {
+        byte.repeat(32).parse().unwrap()
+    }
+
+    const COMMENTED: &'static str = "# Data files for the test suite.
+
+# Keep this one first!
+[blobs.\"zebra.dat\"]
+size = 10 # bytes
+sha256 = \"1111111111111111111111111111111111111111111111111111111111111111\"
+url = \"https://example.com/zebra.dat\"
+compression = \"none\"
+
+[blobs.\"apple.dat\"]
+size = 20
+sha256 = \"2222222222222222222222222222222222222222222222222222222222222222\"
+url = \"https://example.com/apple.dat\"   # the old location
+";
+
+    #[test]
+    fn edits_preserve_layout() {
+        let mut manifest: Manifest = toml::from_str(COMMENTED).unwrap();
+        let mut binfo = BlobInfo::new(30, digest("33"));
+        binfo.set_url("https://example.org/apple.dat");
+        manifest.insert_or_update("apple.dat", binfo, &mut NoopNotificationBackend::new());
+
+        let expected = COMMENTED
+            .replace("size = 20", "size = 30")
+            .replace(&"2".repeat(64), &"3".repeat(64))
+            .replace("https://example.com/apple.dat", "https://example.org/apple.dat");
+        assert_eq!(manifest.to_toml_string(Some(COMMENTED)).unwrap(), expected);
+    }
+
+    #[test]
+    fn edits_add_and_remove_entries() {
+        let mut manifest: Manifest = toml::from_str(COMMENTED).unwrap();
+        manifest.blobs.remove("zebra.dat");
+        manifest.insert_or_update("mango.dat", BlobInfo::new(5, digest("44")), &mut NoopNotificationBackend::new());
+
+        let text = manifest.to_toml_string(Some(COMMENTED)).unwrap();
+        assert!(!text.contains("zebra"));
+        assert!(text.starts_with("# Data files for the test suite.\n"));
+        assert!(text.contains("url = \"https://example.com/apple.dat\"   # the old location\n"));
+
+        let apple = text.find("[blobs.\"apple.dat\"]").unwrap();
+        let mango = text.find("[blobs.\"mango.dat\"]").unwrap();
+        assert!(mango > apple);
+        assert_eq!(toml::from_str::<Manifest>(&text).unwrap(), manifest);
+
+        manifest.blobs.clear();
+        assert_eq!(manifest.to_toml_string(Some(COMMENTED)).unwrap(), "# Data files for the test suite.\n\n");
+    }
+
+    fn with_blobs(entries: &[(&str, &BlobInfo)]) -> Manifest {
+        let mut manifest = Manifest { blobs: HashMap::new() };
+
+        for &(name, binfo) in entries {
+            manifest.blobs.insert(name.to_owned(), binfo.clone());
+        }
+
+        manifest
+    }
+
+    fn with_urls(mut binfo: BlobInfo, urls: &[&str]) -> BlobInfo {
+        for u in urls {
+            binfo.add_url(u);
+        }
+
+        binfo
+    }
+
+    #[test]
+    fn merge_disjoint() {
+        let a = BlobInfo::new(1, digest("11"));
+        let b = BlobInfo::new(2, digest("22"));
+        let c = BlobInfo::new(3, digest("33"));
+        let base = with_blobs(&[("a", &a), ("c", &c)]);
+        let ours = with_blobs(&[("a", &a), ("b", &b)]);
+        let theirs = with_blobs(&[("a", &a), ("c", &c), ("d", &b)]);
+
+        let merged = ours.merge(&base, &theirs, &mut NoopNotificationBackend::new());
+        assert_eq!(merged, with_blobs(&[("a", &a), ("b", &b), ("d", &b)]));
+    }
+
+    #[test]
+    fn merge_conflict_keeps_ours() {
+        let orig = BlobInfo::new(1, digest("11"));
+        let mine = BlobInfo::new(2, digest("22"));
+        let other = with_urls(BlobInfo::new(3, digest("33")), &["https://example.com/a"]);
+        let base = with_blobs(&[("a", &orig)]);
+        let ours = with_blobs(&[("a", &mine)]);
+        let theirs = with_blobs(&[("a", &other)]);
+
+        let merged = ours.merge(&base, &theirs, &mut NoopNotificationBackend::new());
+        assert_eq!(merged, ours);
+
+        // Nor does their change to an entry that we removed survive.
+        let merged = with_blobs(&[]).merge(&base, &theirs, &mut NoopNotificationBackend::new());
+        assert_eq!(merged, with_blobs(&[]));
+    }
+
+    #[test]
+    fn merge_unions_mirrors() {
+        let orig = with_urls(BlobInfo::new(1, digest("11")), &["https://example.com/a"]);
+        let mine = with_urls(orig.clone(), &["https://mirror1.example.com/a", "https://both.example.com/a"]);
+        let other = with_urls(orig.clone(), &["https://both.example.com/a", "https://mirror2.example.com/a"]);
+        let base = with_blobs(&[("a", &orig)]);
+        let ours = with_blobs(&[("a", &mine)]);
+        let theirs = with_blobs(&[("a", &other)]);
+
+        let merged = ours.merge(&base, &theirs, &mut NoopNotificationBackend::new());
+        assert_eq!(merged.lookup("a").unwrap().urls(), vec![
+            "https://example.com/a",
+            "https://mirror1.example.com/a",
+            "https://both.example.com/a",
+            "https://mirror2.example.com/a",
+        ]);
+    }
+}
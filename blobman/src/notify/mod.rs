@@ -79,6 +79,28 @@ pub trait NotificationBackend {
     /// If `err` is not `None`, the information contained in the object should
     /// be reported after the main message.
     fn notify(&mut self, kind: NotificationKind, args: Arguments, err: Option<Error>);
+
+    /// Notify the user that a transfer of data has started.
+    ///
+    /// Several transfers may be in progress at once; *id* identifies this
+    /// one in subsequent progress events. *what* describes the source of the
+    /// data, typically with a URL. *n_bytes* is the amount of data already
+    /// in hand, which is nonzero when a download is being resumed, and
+    /// *total* is the expected size of the data, if it is known — for
+    /// downloads, this comes from the `Content-Length` header. If a transfer
+    /// has to start over, this may be called again with the same *id*.
+    ///
+    /// The default implementation does nothing, as do those of the other
+    /// progress functions.
+    fn progress_start(&mut self, _id: usize, _what: &str, _n_bytes: u64, _total: Option<u64>) {}
+
+    /// Notify the user that the transfer identified by *id* has obtained a
+    /// total of *n_bytes* bytes.
+    fn progress_update(&mut self, _id: usize, _n_bytes: u64) {}
+
+    /// Notify the user that the transfer identified by *id* has finished,
+    /// whether or not it succeeded.
+    fn progress_end(&mut self, _id: usize) {}
 }
 
 
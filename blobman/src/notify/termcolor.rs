@@ -13,8 +13,12 @@ engine. (Which the author of this module also wrote.)
 // TODO: make this module a feature that can be disabled if the user doesn't want to
 // link with termcolor
 
+use atty;
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fmt::Arguments;
 use std::io::Write;
+use std::time::{Duration, Instant};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 use errors::Error;
@@ -25,11 +29,13 @@ use super::{ChatterLevel, NotificationKind, NotificationBackend};
 ///
 /// This struct implements the NotificationBackend trait, and emits
 /// notifications to standard output and standard error with colorized
-/// prefixes.
+/// prefixes. If standard error is a terminal, the progress of transfers is
+/// shown there with a progress bar, unless the chatter level is minimal.
 pub struct TermcolorNotificationBackend {
     chatter: ChatterLevel,
     stdout: StandardStream,
     stderr: StandardStream,
+    progress: Option<ProgressBar>,
     note_spec: ColorSpec,
     //highlight_spec: ColorSpec,
     warning_spec: ColorSpec,
@@ -56,10 +62,17 @@ impl TermcolorNotificationBackend {
         let mut fatal_spec = ColorSpec::new();
         fatal_spec.set_fg(Some(Color::Red)).set_bold(true);
 
+        let progress = if chatter > ChatterLevel::Minimal && atty::is(atty::Stream::Stderr) {
+            Some(ProgressBar::new())
+        } else {
+            None
+        };
+
         TermcolorNotificationBackend {
             chatter: chatter,
             stdout: StandardStream::stdout(ColorChoice::Auto),
             stderr: StandardStream::stderr(ColorChoice::Auto),
+            progress: progress,
             note_spec: note_spec,
             //highlight_spec: highlight_spec,
             warning_spec: warning_spec,
@@ -101,6 +114,10 @@ impl TermcolorNotificationBackend {
     }
 
     fn generic_message(&mut self, kind: NotificationKind, prefix: Option<&str>, args: Arguments) {
+        // The progress bar will be redrawn below the message the next time
+        // that it is updated.
+        self.clear_progress();
+
         let text = match prefix {
             Some(s) => s,
             None => match kind {
@@ -119,6 +136,49 @@ impl TermcolorNotificationBackend {
         });
     }
 
+    /// Redraw the progress bar, if we have one. Unless *force* is true, this
+    /// does nothing if the bar was drawn very recently.
+    fn draw_progress(&mut self, force: bool) {
+        let bar = match self.progress {
+            Some(ref mut b) => b,
+            None => { return; },
+        };
+
+        if bar.transfers.len() == 0 {
+            return;
+        }
+
+        if !force && bar.last_drawn.map(|t| t.elapsed() < Duration::from_millis(REDRAW_INTERVAL_MS)).unwrap_or(false) {
+            return;
+        }
+
+        // The width is in characters, since labels can contain non-ASCII
+        // text.
+        let line = bar.render();
+        let width = line.chars().count();
+        let padding = " ".repeat(bar.width.saturating_sub(width));
+        write!(self.stderr, "\r{}{}", line, padding).expect("failed to write to standard stream");
+        self.stderr.flush().expect("failed to write to standard stream");
+        bar.width = width;
+        bar.last_drawn = Some(Instant::now());
+    }
+
+    /// Erase the progress bar from the terminal, if it is showing.
+    fn clear_progress(&mut self) {
+        let bar = match self.progress {
+            Some(ref mut b) => b,
+            None => { return; },
+        };
+
+        if bar.width == 0 {
+            return;
+        }
+
+        write!(self.stderr, "\r{}\r", " ".repeat(bar.width)).expect("failed to write to standard stream");
+        self.stderr.flush().expect("failed to write to standard stream");
+        bar.width = 0;
+    }
+
 
     // Helpers for the CLI program that aren't needed by the internal bits,
     // so we put them here to minimize the cross-section of the NotificationBackend
@@ -163,4 +223,190 @@ impl NotificationBackend for TermcolorNotificationBackend {
             }
         }
     }
+
+    fn progress_start(&mut self, id: usize, what: &str, n_bytes: u64, total: Option<u64>) {
+        if let Some(ref mut bar) = self.progress {
+            bar.transfers.insert(id, TransferState {
+                what: what.to_owned(),
+                started: Instant::now(),
+                n_initial: n_bytes,
+                n_bytes: n_bytes,
+                total: total,
+            });
+        }
+
+        self.draw_progress(true);
+    }
+
+    fn progress_update(&mut self, id: usize, n_bytes: u64) {
+        if let Some(ref mut bar) = self.progress {
+            if let Some(t) = bar.transfers.get_mut(&id) {
+                t.n_bytes = n_bytes;
+            }
+        }
+
+        self.draw_progress(false);
+    }
+
+    fn progress_end(&mut self, id: usize) {
+        let done = match self.progress {
+            Some(ref mut bar) => {
+                bar.transfers.remove(&id);
+                bar.transfers.len() == 0
+            },
+            None => { return; },
+        };
+
+        if done {
+            self.clear_progress();
+        } else {
+            self.draw_progress(true);
+        }
+    }
+}
+
+
+/// The minimum time between redraws of the progress bar, in milliseconds.
+const REDRAW_INTERVAL_MS: u64 = 100;
+
+/// The number of characters used for the name of the transfer in the
+/// progress bar.
+const LABEL_WIDTH: usize = 16;
+
+/// The number of characters inside the brackets of the progress bar.
+const BAR_WIDTH: usize = 20;
+
+
+/// What we know about one transfer whose progress is being shown.
+struct TransferState {
+    what: String,
+    started: Instant,
+    n_initial: u64,
+    n_bytes: u64,
+    total: Option<u64>,
+}
+
+
+/// The state of the progress bar shown on the terminal.
+///
+/// If several transfers are running at once, the bar shows their combined
+/// progress.
+struct ProgressBar {
+    transfers: BTreeMap<usize, TransferState>,
+    last_drawn: Option<Instant>,
+
+    /// The length of the line currently on the screen, or zero if the bar
+    /// isn't showing.
+    width: usize,
+}
+
+impl ProgressBar {
+    fn new() -> Self {
+        ProgressBar {
+            transfers: BTreeMap::new(),
+            last_drawn: None,
+            width: 0,
+        }
+    }
+
+    /// Produce the text of the progress bar, which should fit comfortably
+    /// on an 80-column terminal.
+    fn render(&self) -> String {
+        let now = Instant::now();
+        let mut started = now;
+        let mut n_bytes = 0;
+        let mut n_new = 0;
+        let mut total = Some(0);
+
+        for t in self.transfers.values() {
+            started = cmp::min(started, t.started);
+            n_bytes += t.n_bytes;
+            n_new += t.n_bytes.saturating_sub(t.n_initial);
+            total = match (total, t.total) {
+                (Some(a), Some(b)) => Some(a + b),
+                _ => None,
+            };
+        }
+
+        let label = if self.transfers.len() == 1 {
+            let what = &self.transfers.values().next().unwrap().what;
+            short_label(what.rsplit('/').find(|s| s.len() > 0).unwrap_or(what))
+        } else {
+            format!("{} transfers", self.transfers.len())
+        };
+
+        let mut line = format!("{:<width$}", label, width = LABEL_WIDTH);
+
+        match total {
+            Some(t) if t > 0 => {
+                let fraction = (n_bytes as f64 / t as f64).min(1.0);
+                let filled = (fraction * BAR_WIDTH as f64) as usize;
+                line.push_str(&format!(" [{}{}] {:3}%", "=".repeat(filled), " ".repeat(BAR_WIDTH - filled),
+                                       (fraction * 100.) as u32));
+            },
+            _ => {
+                line.push_str(&format!(" {}", format_bytes(n_bytes)));
+            },
+        }
+
+        // Don't show a rate until we have a bit of data to base it on.
+        let elapsed = now.duration_since(started);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+
+        if elapsed < 1. || n_new == 0 {
+            return line;
+        }
+
+        let rate = n_new as f64 / elapsed;
+        line.push_str(&format!(" {}/s", format_bytes(rate as u64)));
+
+        if let Some(t) = total {
+            let eta = t.saturating_sub(n_bytes) as f64 / rate;
+            line.push_str(&format!(" ETA {}", format_duration(eta as u64)));
+        }
+
+        line
+    }
+}
+
+
+/// Shorten *text* so that it fits in the label of the progress bar.
+fn short_label(text: &str) -> String {
+    if text.chars().count() <= LABEL_WIDTH {
+        return text.to_owned();
+    }
+
+    let mut label: String = text.chars().take(LABEL_WIDTH - 3).collect();
+    label.push_str("...");
+    label
+}
+
+
+/// Format a number of bytes in a human-friendly way.
+fn format_bytes(n: u64) -> String {
+    const UNITS: [&'static str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+
+    let mut value = n as f64 / 1024.;
+    let mut unit = 0;
+
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+
+/// Format a number of seconds as `M:SS` or `H:MM:SS`.
+fn format_duration(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
 }
@@ -6,25 +6,83 @@ Storing blobs on the filesystem.
 
 */
 
+use filetime::{self, FileTime};
+use fs2::FileExt;
 use mkstemp::TempFile;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
 use std::ffi::OsStr;
-use std::fs;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self as std_io, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use toml;
 
 use digest::DigestData;
 use errors::Result;
 use io;
-use super::{StagingCookie, Storage};
+use super::{Availability, GcReport, PartialData, Staging, StoredBlob, Storage};
+
+
+/// The name of the file in the storage prefix that lists the manifests that
+/// refer to the storage area.
+const REGISTRY_NAME: &'static str = "manifests.toml";
+
+
+/// The name of the file in the storage prefix that lists the pinned blobs.
+const PINS_NAME: &'static str = "pins.toml";
+
+/// The name of the file in the storage prefix used to coordinate access
+/// among multiple processes.
+const LOCK_NAME: &'static str = "lock";
+
+/// The prefix of the names of the files used to lock manifests. The rest of
+/// the name is derived from the path of the manifest.
+
+/// The prefix of the names of temporary files used for staging.
+const STAGING_STEM: &'static str = "staging.";
+
+/// Staging files older than this (in seconds) are assumed to have been
+/// abandoned by a process that died, and are removed during garbage
+/// collection.
+const STALE_STAGING_AGE: u64 = 24 * 60 * 60;
+
+/// The prefix of the names of files holding the data of resumable staging
+/// operations.
+const PARTIAL_STEM: &'static str = "partial.";
+
+/// The suffix added to the name of a partial-data file to get the name of
+/// the file holding its validator.
+const VALIDATOR_SUFFIX: &'static str = ".validator";
+
+/// Partial-data files that haven't been touched in this long (in seconds)
+/// are assumed to be of no further use, and are removed during garbage
+/// collection. This is longer than `STALE_STAGING_AGE` since the whole point
+/// of these files is to survive failures.
+const STALE_PARTIAL_AGE: u64 = 7 * 24 * 60 * 60;
+
+
+/// The serialized form of the manifest registry.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ManifestRegistry {
+    manifests: BTreeSet<PathBuf>,
+}
+
+
+/// The serialized form of the list of pinned blobs.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct PinList {
+    pinned: BTreeSet<DigestData>,
+}
 
 
 /// A storage backend that arranges files on the filesystem
 #[derive(Debug)]
 pub struct FilesystemStorage {
     prefix: PathBuf,
-    next_staging_cookie: usize,
-    staging_paths: HashMap<usize,PathBuf>,
+    max_size: Option<u64>,
+    config_pins: HashSet<DigestData>,
+    readonly: bool,
 }
 
 
@@ -33,34 +91,305 @@ impl FilesystemStorage {
     pub fn new<P: AsRef<OsStr>>(prefix: &P) -> Self {
         Self {
             prefix: PathBuf::from(prefix),
-            next_staging_cookie: 0,
-            staging_paths: HashMap::new(),
+            max_size: None,
+            config_pins: HashSet::new(),
+            readonly: false,
         }
     }
+
+    /// Set whether this storage area is read-only.
+    ///
+    /// A read-only storage area never writes anything to the filesystem: it
+    /// can't stage new blobs, blob usage isn't tracked, manifests aren't
+    /// registered, and nothing is ever evicted or garbage-collected. This is
+    /// useful for caches that are shared among many users, perhaps on a
+    /// network filesystem that's mounted read-only.
+    pub fn set_readonly(&mut self, readonly: bool) -> &mut Self {
+        self.readonly = readonly;
+        self
+    }
+
+    /// Set the maximum total size of the blobs in this storage area, in
+    /// bytes.
+    ///
+    /// If the limit is exceeded, `enforce_budget()` will evict blobs to get
+    /// back under it. `None` means that there is no limit.
+    pub fn set_max_size(&mut self, max_size: Option<u64>) -> &mut Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Pin a blob in this storage area for the lifetime of this object.
+    ///
+    /// This is used to implement pins specified in the user configuration;
+    /// unlike `Storage::set_pinned()`, it does not persist anything on disk.
+    pub fn pin_transiently(&mut self, digest: &DigestData) -> &mut Self {
+        self.config_pins.insert(*digest);
+        self
+    }
+
+    /// List all of the blobs contained in this storage area.
+    ///
+    /// Blobs are stored in files whose paths are derived from their digests
+    /// as described in `DigestData::two_part_path()`. Any other files
+    /// in the storage area are ignored.
+    pub fn enumerate(&self) -> Result<Vec<StoredBlob>> {
+        let mut blobs = Vec::new();
+
+        let entries = match fs::read_dir(&self.prefix) {
+            Ok(e) => e,
+            Err(ref e) if e.kind() == std_io::ErrorKind::NotFound => { return Ok(blobs); },
+            Err(e) => { return Err(e.into()); },
+        };
+
+        for entry in entries {
+            let entry = ctry!(entry; "couldn't read directory {}", self.prefix.display());
+
+            let dir_name = match entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(_) => { continue; },
+            };
+
+            if dir_name.len() != 2 || !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let dir_path = entry.path();
+
+            for subentry in ctry!(fs::read_dir(&dir_path); "couldn't read directory {}", dir_path.display()) {
+                let subentry = ctry!(subentry; "couldn't read directory {}", dir_path.display());
+
+                let digest: DigestData = match subentry.file_name().to_str() {
+                    Some(rest) => match format!("{}{}", dir_name, rest).parse() {
+                        Ok(d) => d,
+                        Err(_) => { continue; },
+                    },
+                    None => { continue; },
+                };
+
+                let path = subentry.path();
+                let md = ctry!(fs::symlink_metadata(&path); "couldn't get info for file {}", path.display());
+
+                if !md.is_file() {
+                    continue;
+                }
+
+                blobs.push(StoredBlob {
+                    digest: digest,
+                    path: path,
+                    size: md.len(),
+                    n_links: n_links(&md),
+                    last_used: md.accessed().or_else(|_| md.modified()).unwrap_or(UNIX_EPOCH),
+                    committed: md.modified().unwrap_or(UNIX_EPOCH),
+                });
+            }
+        }
+
+        Ok(blobs)
+    }
+
+    /// Find staging files that were abandoned a long time ago.
+    ///
+    /// Staging files are normally cleaned up when their `FilesystemStaging`
+    /// handle is dropped, but that doesn't happen if the process is killed.
+    /// The data of interrupted resumable staging operations are kept on
+    /// purpose, but not forever.
+    fn find_stale_staging(&self) -> Result<Vec<PathBuf>> {
+        let mut stale = self.find_old_files(STAGING_STEM, STALE_STAGING_AGE)?;
+        stale.extend(self.find_old_files(PARTIAL_STEM, STALE_PARTIAL_AGE)?);
+        Ok(stale)
+    }
+
+    /// Find files in the storage prefix whose names start with *stem* and
+    /// that were last modified more than *max_age* seconds ago.
+    fn find_old_files(&self, stem: &str, max_age: u64) -> Result<Vec<PathBuf>> {
+        let mut stale = Vec::new();
+        let max_age = Duration::from_secs(max_age);
+        let now = SystemTime::now();
+
+        let entries = match fs::read_dir(&self.prefix) {
+            Ok(e) => e,
+            Err(ref e) if e.kind() == std_io::ErrorKind::NotFound => { return Ok(stale); },
+            Err(e) => { return Err(e.into()); },
+        };
+
+        for entry in entries {
+            let entry = ctry!(entry; "couldn't read directory {}", self.prefix.display());
+
+            if !entry.file_name().to_str().map(|n| n.starts_with(stem)).unwrap_or(false) {
+                continue;
+            }
+
+            let path = entry.path();
+            let md = ctry!(fs::symlink_metadata(&path); "couldn't get info for file {}", path.display());
+
+            if !md.is_file() {
+                continue;
+            }
+
+            let modified = match md.modified() {
+                Ok(t) => t,
+                Err(_) => { continue; },
+            };
+
+            // If the modification time is in the future, `duration_since`
+            // fails and we leave the file alone.
+            if let Ok(age) = now.duration_since(modified) {
+                if age > max_age {
+                    stale.push(path);
+                }
+            }
+        }
+
+        Ok(stale)
+    }
+
+    /// Lock the storage area against modifications by other processes.
+    ///
+    /// The lock is held until the returned value is dropped. Read-only
+    /// storage areas are never modified by us, so we don't bother locking
+    /// them.
+    fn lock(&self) -> Result<Option<File>> {
+        if self.readonly {
+            return Ok(None);
+        }
+
+        lock_prefix(&self.prefix).map(Some)
+    }
+
+    /// Load a TOML data file from the storage prefix.
+    ///
+    /// If the file does not exist, a default value is returned.
+    fn load_toml<T>(&self, name: &str) -> Result<T> where T: Default + for<'de> Deserialize<'de> {
+        let path = self.prefix.join(name);
+
+        match ctry!(io::try_open(&path); "couldn't open {}", path.display()) {
+            Some(mut f) => {
+                let mut buf = Vec::<u8>::new();
+                ctry!(f.read_to_end(&mut buf); "couldn't read {}", path.display());
+                Ok(ctry!(toml::from_slice(&buf); "couldn't parse {}", path.display()))
+            },
+            None => Ok(T::default()),
+        }
+    }
+
+    /// Save a TOML data file into the storage prefix.
+    fn save_toml<T: Serialize>(&self, name: &str, data: &T) -> Result<()> {
+        let path = self.prefix.join(name);
+        ctry!(fs::create_dir_all(&self.prefix); "couldn't create directory {}", self.prefix.display());
+        let text = toml::ser::to_string_pretty(data)?;
+        ctry!(io::atomic_write(&path, text.as_bytes()); "couldn't write {}", path.display());
+        Ok(())
+    }
+
+    /// Load the manifest registry, pruning entries for manifests that no
+    /// longer exist.
+    ///
+    /// Returns the registry and a flag indicating whether any entries were
+    /// pruned.
+    fn load_registry(&self) -> Result<(ManifestRegistry, bool)> {
+        let mut registry: ManifestRegistry = self.load_toml(REGISTRY_NAME)?;
+        let n_orig = registry.manifests.len();
+        registry.manifests = registry.manifests.into_iter().filter(|p| p.exists()).collect();
+        let pruned = registry.manifests.len() != n_orig;
+        Ok((registry, pruned))
+    }
+
+    /// Get the set of all pinned blobs, whether pinned in the configuration
+    /// or on disk.
+    fn all_pins(&self) -> Result<HashSet<DigestData>> {
+        let pins: PinList = self.load_toml(PINS_NAME)?;
+        let mut all = self.config_pins.clone();
+        all.extend(pins.pinned);
+        Ok(all)
+    }
+
+    /// Record that a blob has just been used.
+    ///
+    /// We track usage by setting the access time of the blob file, leaving
+    /// the modification time alone. This is best-effort: if it fails, the
+    /// only consequence is that the blob may be evicted a bit too soon.
+    fn touch(&self, path: &Path) {
+        if self.readonly {
+            return;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        if let Ok(md) = fs::metadata(path) {
+            let atime = FileTime::from_seconds_since_1970(now.as_secs(), now.subsec_nanos());
+            let mtime = FileTime::from_last_modification_time(&md);
+            let _ = filetime::set_file_times(path, atime, mtime);
+        }
+    }
+}
+
+
+/// Lock a storage prefix, creating it if needed.
+fn lock_prefix(prefix: &Path) -> Result<File> {
+    ctry!(fs::create_dir_all(prefix); "couldn't create directory {}", prefix.display());
+    io::lock_exclusive(prefix.join(LOCK_NAME))
+}
+
+
+/// Get the number of hard links to a file.
+///
+/// On platforms where we can't find this out, we pretend that there is only
+/// one.
+#[cfg(unix)]
+fn n_links(md: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    md.nlink()
+}
+
+#[cfg(not(unix))]
+fn n_links(_md: &fs::Metadata) -> u64 {
+    1
 }
 
 
 impl Storage for FilesystemStorage {
-    fn get_path(&self, digest: &DigestData) -> Result<Option<PathBuf>> {
-        let path = ctry!(digest.create_two_part_path(&self.prefix);
-                         "couldn't make directories in {}", self.prefix.display());
+    fn get_path(&mut self, digest: &DigestData) -> Result<Option<PathBuf>> {
+        let path = digest.two_part_path(&self.prefix);
 
         if path.exists() {
+            self.touch(&path);
             Ok(Some(path))
         } else {
             Ok(None)
         }
     }
 
-    fn open(&self, digest: &DigestData) -> Result<Option<Box<Read>>> {
-        let path = ctry!(digest.create_two_part_path(&self.prefix);
-                         "couldn't make directories in {}", self.prefix.display());
-        Ok(io::try_open(path)?.map(|f| Box::new(f) as Box<Read>))
+    fn open(&mut self, digest: &DigestData) -> Result<Option<Box<Read>>> {
+        let path = digest.two_part_path(&self.prefix);
+        let f = io::try_open(&path)?;
+
+        if f.is_some() {
+            self.touch(&path);
+        }
+
+        Ok(f.map(|f| Box::new(f) as Box<Read>))
+    }
+
+    fn locate(&self, digest: &DigestData) -> Result<Availability> {
+        let path = digest.two_part_path(&self.prefix);
+
+        if path.exists() {
+            Ok(Availability::File(path))
+        } else {
+            Ok(Availability::Missing)
+        }
     }
 
-    fn start_staging<'a>(&'a mut self) -> Result<(Box<Write>, StagingCookie)> {
+    fn start_staging(&mut self) -> Result<Box<Staging>> {
+        if self.readonly {
+            return err_msg!("cannot add blobs to read-only storage area {}", self.prefix.display());
+        }
+
+        ctry!(fs::create_dir_all(&self.prefix); "couldn't create directory {}", self.prefix.display());
+
         let mut p = self.prefix.clone();
-        p.push("staging.XXXXXXXX");
+        p.push(format!("{}XXXXXXXX", STAGING_STEM));
 
         // Unfortunately mkstemp-rs wants its input paths to be str's, not
         // OsStr's. To be paranoid we refuse to run if we can't convert
@@ -74,25 +403,393 @@ impl Storage for FilesystemStorage {
         };
 
         let tempfile = ctry!(TempFile::new(template, false); "couldn\'t create temporary file with template {}", template);
+        let path = PathBuf::from(tempfile.path());
+
+        Ok(Box::new(FilesystemStaging {
+            file: Some(tempfile),
+            path: path,
+            prefix: self.prefix.clone(),
+        }))
+    }
+
+    fn start_resumable_staging(&mut self, key: &DigestData) -> Result<Box<Staging>> {
+        if self.readonly {
+            return err_msg!("cannot add blobs to read-only storage area {}", self.prefix.display());
+        }
+
+        ctry!(fs::create_dir_all(&self.prefix); "couldn't create directory {}", self.prefix.display());
 
-        let cookie = self.next_staging_cookie;
-        self.next_staging_cookie += 1;
-        self.staging_paths.insert(cookie, PathBuf::from(tempfile.path()));
+        let name = format!("{}{}", PARTIAL_STEM, key.to_string());
+        let path = self.prefix.join(&name);
+        let validator_path = self.prefix.join(format!("{}{}", name, VALIDATOR_SUFFIX));
 
-        Ok((Box::new(tempfile), cookie))
+        let file = ctry!(OpenOptions::new().read(true).append(true).create(true).open(&path);
+                         "couldn't open {}", path.display());
+
+        // If another process is working on the same transfer, we stay out of
+        // its way and start from scratch.
+        if file.try_lock_exclusive().is_err() {
+            return self.start_staging();
+        }
+
+        let size = ctry!(file.metadata(); "couldn't get info for file {}", path.display()).len();
+
+        let validator = if size == 0 {
+            None
+        } else {
+            match ctry!(io::try_open(&validator_path); "couldn't open {}", validator_path.display()) {
+                Some(mut f) => {
+                    let mut text = String::new();
+                    ctry!(f.read_to_string(&mut text); "couldn't read {}", validator_path.display());
+                    let text = text.trim();
+
+                    if text.len() > 0 {
+                        Some(text.to_owned())
+                    } else {
+                        None
+                    }
+                },
+                None => None,
+            }
+        };
+
+        Ok(Box::new(ResumableStaging {
+            file: Some(file),
+            path: path,
+            validator_path: validator_path,
+            prefix: self.prefix.clone(),
+            size: size,
+            validator: validator,
+        }))
     }
 
-    fn finish_staging(&mut self, cookie: StagingCookie, digest: &DigestData) -> Result<()> {
-        let src_path = self.staging_paths.remove(&cookie).unwrap();
-        let dest_path = ctry!(digest.create_two_part_path(&self.prefix);
-                              "couldn't make directories in {}", self.prefix.display());
-        ctry!(fs::rename(&src_path, &dest_path);
-              "couldn't rename {} to {}", src_path.display(), dest_path.display());
+    fn gc(&mut self, keep: &HashSet<DigestData>, started: SystemTime, dry_run: bool) -> Result<GcReport> {
+        let mut report = GcReport::default();
+
+        if self.readonly {
+            return Ok(report);
+        }
 
-        let mut perms = ctry!(fs::metadata(&dest_path); "couldn't get info for file {}", dest_path.display()).permissions();
-        perms.set_readonly(true);
-        ctry!(fs::set_permissions(&dest_path, perms); "couldn\'t make file {} read-only", dest_path.display());
+        let _lock = self.lock()?;
+        let pins = self.all_pins()?;
 
+        for blob in self.enumerate()? {
+            if keep.contains(&blob.digest) || pins.contains(&blob.digest) || blob.committed >= started {
+                continue;
+            }
+
+            if blob.n_links > 1 {
+                report.linked.push(blob);
+                continue;
+            }
+
+            if !dry_run {
+                ctry!(io::try_remove_file(&blob.path); "couldn't remove file {}", blob.path.display());
+            }
+
+            report.removed.push(blob);
+        }
+
+        for path in self.find_stale_staging()? {
+            if !dry_run {
+                ctry!(io::try_remove_file(&path); "couldn't remove file {}", path.display());
+            }
+
+            report.stale_staging.push(path);
+        }
+
+        Ok(report)
+    }
+
+    fn register_manifest(&mut self, path: &Path) -> Result<()> {
+        if self.readonly {
+            return Ok(());
+        }
+
+        let _lock = self.lock()?;
+        let (mut registry, pruned) = self.load_registry()?;
+        let added = registry.manifests.insert(path.to_path_buf());
+
+        if added || pruned {
+            self.save_toml(REGISTRY_NAME, &registry)?;
+        }
+
+        Ok(())
+    }
+
+    fn registered_manifests(&mut self) -> Result<Vec<PathBuf>> {
+        let _lock = self.lock()?;
+        let (registry, pruned) = self.load_registry()?;
+
+        if pruned && !self.readonly {
+            self.save_toml(REGISTRY_NAME, &registry)?;
+        }
+
+        Ok(registry.manifests.into_iter().collect())
+    }
+
+    fn set_pinned(&mut self, digest: &DigestData, pinned: bool) -> Result<()> {
+        // Nothing is ever evicted from a read-only area, so pins are moot.
+        if self.readonly {
+            return Ok(());
+        }
+
+        let _lock = self.lock()?;
+        let mut pins: PinList = self.load_toml(PINS_NAME)?;
+
+        let changed = if pinned {
+            pins.pinned.insert(*digest)
+        } else {
+            pins.pinned.remove(digest)
+        };
+
+        if changed {
+            self.save_toml(PINS_NAME, &pins)?;
+        }
+
+        Ok(())
+    }
+
+    fn enforce_budget(&mut self, keep: &HashSet<DigestData>) -> Result<Vec<StoredBlob>> {
+        let mut evicted = Vec::new();
+
+        let max_size = match self.max_size {
+            Some(s) if !self.readonly => s,
+            _ => { return Ok(evicted); },
+        };
+
+        let _lock = self.lock()?;
+        let mut blobs = self.enumerate()?;
+        let mut total_size: u64 = blobs.iter().map(|b| b.size).sum();
+
+        if total_size <= max_size {
+            return Ok(evicted);
+        }
+
+        let pins = self.all_pins()?;
+        blobs.sort_by_key(|b| b.last_used);
+
+        for blob in blobs {
+            if total_size <= max_size {
+                break;
+            }
+
+            // Evicting a blob that is hard-linked elsewhere wouldn't free up
+            // any space.
+            if keep.contains(&blob.digest) || pins.contains(&blob.digest) || blob.n_links > 1 {
+                continue;
+            }
+
+            ctry!(io::try_remove_file(&blob.path); "couldn't remove file {}", blob.path.display());
+            total_size -= blob.size;
+            evicted.push(blob);
+        }
+
+        Ok(evicted)
+    }
+}
+
+
+/// A handle to a blob being staged into a FilesystemStorage.
+///
+/// The data are written to a temporary file in the storage prefix, which is
+/// renamed into place when the staging is committed. If the handle is dropped
+/// before then, the temporary file is deleted.
+pub struct FilesystemStaging {
+    file: Option<TempFile>,
+    path: PathBuf,
+    prefix: PathBuf,
+}
+
+impl Write for FilesystemStaging {
+    fn write(&mut self, buf: &[u8]) -> std_io::Result<usize> {
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std_io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+impl Staging for FilesystemStaging {
+    fn commit(mut self: Box<Self>, digest: &DigestData) -> Result<()> {
+        // Close the file before moving it.
+        self.file = None;
+        file_staged_data(&self.path, &self.prefix, digest)?;
+
+        // The temporary file is gone now, so there's nothing for our Drop
+        // implementation to clean up.
+        self.path = PathBuf::new();
+        Ok(())
+    }
+
+    fn clone_file(&mut self, src: &File) -> Result<bool> {
+        reflink(src, &self.path)
+    }
+}
+
+impl Drop for FilesystemStaging {
+    fn drop(&mut self) {
+        self.file = None;
+
+        if self.path.as_os_str().len() > 0 {
+            let _ = io::try_remove_file(&self.path);
+        }
+    }
+}
+
+
+/// A handle to a blob being staged into a FilesystemStorage in a way that
+/// can be resumed if it's interrupted.
+///
+/// The data are written to a file in the storage prefix whose name is
+/// derived from the transfer key. The file is locked while the handle
+/// exists. The data that it holds are reported by `partial()`, including
+/// anything written through this handle, so that a transfer can also be
+/// resumed if it fails partway through within one process. If the handle is
+/// dropped without being committed, the file is left in place for a future
+/// attempt; files that go unused for a long time are removed during garbage
+/// collection.
+pub struct ResumableStaging {
+    file: Option<File>,
+    path: PathBuf,
+    validator_path: PathBuf,
+    prefix: PathBuf,
+    size: u64,
+    validator: Option<String>,
+}
+
+impl Write for ResumableStaging {
+    fn write(&mut self, buf: &[u8]) -> std_io::Result<usize> {
+        let n = self.file.as_mut().unwrap().write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std_io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+impl Staging for ResumableStaging {
+    fn commit(mut self: Box<Self>, digest: &DigestData) -> Result<()> {
+        self.file = None;
+        file_staged_data(&self.path, &self.prefix, digest)?;
+        ctry!(io::try_remove_file(&self.validator_path); "couldn't remove file {}", self.validator_path.display());
+        Ok(())
+    }
+
+    fn partial(&self) -> Option<PartialData> {
+        if self.size == 0 {
+            return None;
+        }
+
+        Some(PartialData {
+            size: self.size,
+            validator: self.validator.clone(),
+        })
+    }
+
+    fn read_partial(&mut self) -> Result<Option<Box<Read>>> {
+        if self.size == 0 {
+            return Ok(None);
+        }
+
+        let f = ctry!(File::open(&self.path); "couldn't open {}", self.path.display());
+        Ok(Some(Box::new(f.take(self.size))))
+    }
+
+    fn discard_partial(&mut self) -> Result<()> {
+        // The file is opened in append mode, so subsequent writes will go to
+        // the new end of the file.
+        ctry!(self.file.as_mut().unwrap().set_len(0); "couldn't truncate {}", self.path.display());
+        ctry!(io::try_remove_file(&self.validator_path); "couldn't remove file {}", self.validator_path.display());
+        self.size = 0;
+        self.validator = None;
         Ok(())
     }
+
+    fn set_validator(&mut self, validator: Option<&str>) -> Result<()> {
+        match validator {
+            Some(v) => {
+                ctry!(io::atomic_write(&self.validator_path, v.as_bytes());
+                      "couldn't write {}", self.validator_path.display());
+            },
+            None => {
+                ctry!(io::try_remove_file(&self.validator_path);
+                      "couldn't remove file {}", self.validator_path.display());
+            },
+        }
+
+        self.validator = validator.map(|v| v.to_owned());
+        Ok(())
+    }
+
+    fn clone_file(&mut self, src: &File) -> Result<bool> {
+        if !reflink(src, &self.path)? {
+            return Ok(false);
+        }
+
+        // The cloned data have nothing to do with whatever we were resuming.
+        ctry!(io::try_remove_file(&self.validator_path); "couldn't remove file {}", self.validator_path.display());
+        self.size = ctry!(src.metadata(); "couldn't get info for the file being cloned").len();
+        self.validator = None;
+        Ok(true)
+    }
+}
+
+
+/// Try to make the file at *dest_path* share the contents of *src*.
+///
+/// This uses the Linux `FICLONE` ioctl, which only works on filesystems such
+/// as Btrfs and XFS, and only within a single filesystem. We open a new
+/// handle since the ioctl refuses to operate on files opened for appending.
+/// If cloning isn't possible, `false` is returned and *dest_path* is left
+/// alone.
+///
+/// The ioctl number depends on the architecture's encoding of ioctl
+/// requests, so we only try this on architectures where we know it.
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64")))]
+fn reflink(src: &File, dest_path: &Path) -> Result<bool> {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+
+    // _IOW(0x94, 9, int) in the generic encoding.
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+
+    let dest = ctry!(OpenOptions::new().write(true).open(dest_path); "couldn't open {}", dest_path.display());
+    let rv = unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE as _, src.as_raw_fd()) };
+    Ok(rv == 0)
+}
+
+#[cfg(not(all(target_os = "linux",
+              any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64"))))]
+fn reflink(_src: &File, _dest_path: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+
+/// Move a fully staged blob file into its final location in the storage
+/// area and make it read-only.
+fn file_staged_data(path: &Path, prefix: &Path, digest: &DigestData) -> Result<()> {
+    let _lock = lock_prefix(prefix)?;
+
+    let dest_path = ctry!(digest.create_two_part_path(prefix);
+                          "couldn't make directories in {}", prefix.display());
+    ctry!(fs::rename(path, &dest_path);
+          "couldn't rename {} to {}", path.display(), dest_path.display());
+
+    // The modification time records when the blob was stored, which garbage
+    // collection needs to know; see `StoredBlob::committed`.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let now = FileTime::from_seconds_since_1970(now.as_secs(), now.subsec_nanos());
+    ctry!(filetime::set_file_times(&dest_path, now, now);
+          "couldn't set the modification time of {}", dest_path.display());
+
+    let mut perms = ctry!(fs::metadata(&dest_path); "couldn't get info for file {}", dest_path.display()).permissions();
+    perms.set_readonly(true);
+    ctry!(fs::set_permissions(&dest_path, perms); "couldn\'t make file {} read-only", dest_path.display());
+
+    Ok(())
 }
@@ -6,50 +6,228 @@ Backends for storing blobs.
 
 */
 
+use std::collections::HashSet;
+use std::fs::File;
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use digest::DigestData;
 use errors::Result;
 
 
 pub mod filesystem;
+pub mod tiered;
 
 
-/// An type alias referring to a particular staging job.
-pub type StagingCookie = usize;
+/// Where, if anywhere, a blob can be found in a storage area.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Availability {
+    /// The blob is stored as a standalone file at the specified path.
+    File(PathBuf),
+
+    /// The blob's data can be read, but it is not available as a standalone
+    /// file.
+    Stream,
+
+    /// The blob is not present.
+    Missing,
+}
+
+
+/// Information about a blob file found in a storage area.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoredBlob {
+    /// The digest of the blob.
+    pub digest: DigestData,
+
+    /// The path of the file holding the blob data.
+    pub path: PathBuf,
+
+    /// The size of the blob, in bytes.
+    pub size: u64,
+
+    /// The number of hard links to the blob file, including the one in the
+    /// storage area itself.
+    pub n_links: u64,
+
+    /// The last time that the blob was used.
+    pub last_used: SystemTime,
+
+    /// The time at which the blob was stored.
+    pub committed: SystemTime,
+}
+
+
+/// A summary of the results of a garbage collection operation.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcReport {
+    /// Blobs that were removed, or would have been removed in a dry run.
+    pub removed: Vec<StoredBlob>,
+
+    /// Unreferenced blobs that were left alone because hard links to them
+    /// exist outside of the storage area. Removing them would not free up
+    /// any space.
+    pub linked: Vec<StoredBlob>,
+
+    /// Leftover staging files from interrupted operations that were removed,
+    /// or would have been removed in a dry run.
+    pub stale_staging: Vec<PathBuf>,
+}
+
+impl GcReport {
+    /// Get the total number of bytes freed by removing blobs.
+    pub fn bytes_freed(&self) -> u64 {
+        self.removed.iter().map(|b| b.size).sum()
+    }
+}
+
+
+/// Data retained from an earlier, interrupted attempt to stage a blob.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PartialData {
+    /// The number of bytes that were retained.
+    pub size: u64,
+
+    /// An opaque string identifying the version of the source data that was
+    /// being retrieved, such as an HTTP entity tag, if one was recorded.
+    pub validator: Option<String>,
+}
+
+
+/// A handle to a blob that is in the process of being added to a storage
+/// area.
+///
+/// Blob data are written to the handle, and then `commit()` is called to
+/// complete the transaction. If the handle is dropped without being
+/// committed — say, because a download failed partway through — the partial
+/// data are discarded.
+pub trait Staging: Write {
+    /// Called when all blob data have been written.
+    ///
+    /// The data are filed away under *digest*, which the caller is
+    /// responsible for computing. An error should be returned if there was a
+    /// problem completing the staging process.
+    fn commit(self: Box<Self>, digest: &DigestData) -> Result<()>;
+
+    /// Get information about data retained from an earlier attempt to stage
+    /// this blob.
+    ///
+    /// Only handles obtained from `Storage::start_resumable_staging()` can
+    /// have such data. Anything written to the handle is appended to them.
+    fn partial(&self) -> Option<PartialData> {
+        None
+    }
+
+    /// Open the data retained from an earlier attempt for reading.
+    fn read_partial(&mut self) -> Result<Option<Box<Read>>> {
+        Ok(None)
+    }
+
+    /// Throw away any retained data, so that staging starts from scratch.
+    fn discard_partial(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Record a string identifying the version of the source data, to be
+    /// checked if this staging operation has to be resumed later.
+    fn set_validator(&mut self, _validator: Option<&str>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Try to fill this handle with the contents of *src* without copying
+    /// them, as with a reflink on a copy-on-write filesystem.
+    ///
+    /// If this succeeds, any data previously written to the handle are
+    /// replaced and `true` is returned. If `false` is returned, nothing has
+    /// changed and the caller should copy the data the usual way.
+    fn clone_file(&mut self, _src: &File) -> Result<bool> {
+        Ok(false)
+    }
+}
+
 
 /// A trait for backends that can store and retrieve blobs.
 ///
 /// I originally implemented this with an associated type for the staging
 /// functionality, but we pass around Storage implementors as trait objects,
 /// and it seems that you basically can't use associated types with trait
-/// objects in a generic fashion. The new API feels less classy but should
-/// work just fine.
+/// objects in a generic fashion. So staging handles are trait objects too.
 pub trait Storage {
     /// Get a path to a blob, if possible.
     ///
     /// Blobs are identified by their digests. If the blob is not present in
     /// this Storage, or this Storage does not store this blob as a standalone
     /// file on the filesystem, that's OK; `Ok(None)` should be returned.
-    fn get_path(&self, digest: &DigestData) -> Result<Option<PathBuf>>;
+    ///
+    /// Calling this function counts as a use of the blob, which may have
+    /// side effects such as updating usage-tracking information.
+    fn get_path(&mut self, digest: &DigestData) -> Result<Option<PathBuf>>;
 
     /// Open a blob, if possible.
     ///
     /// Blobs are identified by their digests. If the blob is not present in
     /// this Storage, that's OK; `Ok(None)` should be returned.
-    fn open(&self, digest: &DigestData) -> Result<Option<Box<Read>>>;
+    ///
+    /// Like `get_path()`, this counts as a use of the blob.
+    fn open(&mut self, digest: &DigestData) -> Result<Option<Box<Read>>>;
+
+    /// Determine whether a blob is present in this Storage.
+    ///
+    /// Unlike `get_path()` and `open()`, this must not have any side effects.
+    fn locate(&self, digest: &DigestData) -> Result<Availability>;
+
+    /// Start staging a new blob.
+    ///
+    /// Any number of staging operations may be in progress at once.
+    fn start_staging(&mut self) -> Result<Box<Staging>>;
+
+    /// Start staging a blob in a way that survives interruptions.
+    ///
+    /// The *key* identifies the transfer, and should be derived from both the
+    /// source of the data and the digest that they are expected to have. If
+    /// an earlier attempt with the same key was interrupted, the returned
+    /// handle holds the data that it retrieved; see `Staging::partial()`.
+    /// Unlike other staging handles, one that is dropped without being
+    /// committed keeps its data for a future attempt. Backends that can't
+    /// support this may behave just like `start_staging()`.
+    fn start_resumable_staging(&mut self, _key: &DigestData) -> Result<Box<Staging>> {
+        self.start_staging()
+    }
+
+    /// Remove stored blobs that are no longer needed.
+    ///
+    /// Every blob whose digest is not contained in *keep* is a candidate for
+    /// removal, except for blobs that were stored at or after *started*: the
+    /// process that stored them may not have recorded them in its manifest
+    /// yet. Implementations should also clean up any data left behind by
+    /// staging operations that were abandoned long ago. If *dry_run* is
+    /// true, nothing is actually deleted, but the returned report describes
+    /// what would have been done.
+    fn gc(&mut self, keep: &HashSet<DigestData>, started: SystemTime, dry_run: bool) -> Result<GcReport>;
+
+    /// Record that a manifest file refers to this storage area.
+    ///
+    /// The *path* should be absolute. Registering the same manifest more than
+    /// once is harmless.
+    fn register_manifest(&mut self, path: &Path) -> Result<()>;
+
+    /// Get the paths of all manifests known to refer to this storage area.
+    ///
+    /// Registered manifests that no longer exist are dropped from the
+    /// registry as a side effect.
+    fn registered_manifests(&mut self) -> Result<Vec<PathBuf>>;
 
-    /// Start staging a new file.
+    /// Mark a blob as pinned or unpinned.
     ///
-    /// Staging is performed by creating a "stager" object. Blob data is
-    /// written to the it, and then a wrap-up function is called to complete
-    /// the transaction.
-    fn start_staging(&mut self) -> Result<(Box<Write>, StagingCookie)>;
+    /// Pinned blobs are never evicted by `enforce_budget()`.
+    fn set_pinned(&mut self, digest: &DigestData, pinned: bool) -> Result<()>;
 
-    /// Called when all blob data have been processed.
+    /// Evict blobs until the storage area fits within its size budget.
     ///
-    /// An error should be returned if there was a problem completing
-    /// the staging process.
-    fn finish_staging(&mut self, cookie: StagingCookie, digest: &DigestData) -> Result<()>;
+    /// If the storage area has no budget, nothing happens. Otherwise, the
+    /// least recently used blobs are removed first. Pinned blobs and blobs
+    /// whose digests are contained in *keep* are never removed. The blobs
+    /// that were evicted are returned.
+    fn enforce_budget(&mut self, keep: &HashSet<DigestData>) -> Result<Vec<StoredBlob>>;
 }
@@ -0,0 +1,222 @@
+// Copyright 2017 Peter Williams and collaborators
+// Licensed under the MIT License.
+
+/*!
+Combining several storage areas into an ordered list of tiers.
+
+*/
+
+use std::collections::HashSet;
+use std::io::{self as std_io, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use digest::{DigestData, Shim};
+use errors::Result;
+use super::{Availability, GcReport, Staging, StoredBlob, Storage};
+
+
+/// One storage area in a TieredStorage.
+struct Tier {
+    storage: Box<Storage>,
+    accepts_ingest: bool,
+    promote: bool,
+}
+
+
+/// A storage backend that layers several other backends.
+///
+/// The tiers are ordered by priority, with the fastest (or otherwise most
+/// preferable) storage coming first. Lookups fall through the tiers in order.
+/// When a blob is found in a lower tier, it can be copied up into higher
+/// tiers that have been configured to accept such promotions. New blobs are
+/// staged into the first tier that accepts new ingests.
+pub struct TieredStorage {
+    tiers: Vec<Tier>,
+}
+
+
+impl TieredStorage {
+    /// Create and return a new TieredStorage object with no tiers.
+    pub fn new() -> Self {
+        Self {
+            tiers: Vec::new(),
+        }
+    }
+
+    /// Add a tier at the lowest priority.
+    ///
+    /// If *accepts_ingest* is true, new blobs may be staged into this tier.
+    /// If *promote* is true, blobs found in lower-priority tiers will be
+    /// copied into this one when they are used.
+    pub fn add_tier(&mut self, storage: Box<Storage>, accepts_ingest: bool, promote: bool) -> &mut Self {
+        self.tiers.push(Tier {
+            storage: storage,
+            accepts_ingest: accepts_ingest,
+            promote: promote,
+        });
+        self
+    }
+
+    /// Copy a blob found in tier *src* into all higher-priority tiers that
+    /// accept promotions.
+    ///
+    /// Returns the index of the highest-priority tier that now holds the
+    /// blob, if any promotions succeeded. Promotion is an optimization, so
+    /// failures are not reported.
+    fn promote(&mut self, src: usize, digest: &DigestData) -> Option<usize> {
+        let mut best = None;
+
+        for dest in 0..src {
+            if !self.tiers[dest].promote {
+                continue;
+            }
+
+            if self.copy_blob(src, dest, digest).is_ok() && best.is_none() {
+                best = Some(dest);
+            }
+        }
+
+        best
+    }
+
+    /// Copy a blob from one tier to another, checking its digest along the
+    /// way.
+    fn copy_blob(&mut self, src: usize, dest: usize, digest: &DigestData) -> Result<()> {
+        let (head, tail) = self.tiers.split_at_mut(src);
+
+        let mut stream = match tail[0].storage.open(digest)? {
+            Some(s) => s,
+            None => { return err_msg!("blob {} disappeared from storage", digest.to_string()); },
+        };
+
+        let mut shim = Shim::new(head[dest].storage.start_staging()?);
+        std_io::copy(&mut stream, &mut shim)?;
+        let (staging, observed) = shim.finish();
+
+        if observed != *digest {
+            return err_msg!("stored data for blob {} are corrupt", digest.to_string());
+        }
+
+        staging.commit(digest)
+    }
+}
+
+
+impl Storage for TieredStorage {
+    fn get_path(&mut self, digest: &DigestData) -> Result<Option<PathBuf>> {
+        for i in 0..self.tiers.len() {
+            if let Some(p) = self.tiers[i].storage.get_path(digest)? {
+                if let Some(j) = self.promote(i, digest) {
+                    if let Some(promoted) = self.tiers[j].storage.get_path(digest)? {
+                        return Ok(Some(promoted));
+                    }
+                }
+
+                return Ok(Some(p));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn open(&mut self, digest: &DigestData) -> Result<Option<Box<Read>>> {
+        for i in 0..self.tiers.len() {
+            if let Some(s) = self.tiers[i].storage.open(digest)? {
+                if let Some(j) = self.promote(i, digest) {
+                    if let Some(promoted) = self.tiers[j].storage.open(digest)? {
+                        return Ok(Some(promoted));
+                    }
+                }
+
+                return Ok(Some(s));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn locate(&self, digest: &DigestData) -> Result<Availability> {
+        for tier in &self.tiers {
+            match tier.storage.locate(digest)? {
+                Availability::Missing => {},
+                a => { return Ok(a); },
+            }
+        }
+
+        Ok(Availability::Missing)
+    }
+
+    fn start_staging(&mut self) -> Result<Box<Staging>> {
+        for tier in &mut self.tiers {
+            if tier.accepts_ingest {
+                return tier.storage.start_staging();
+            }
+        }
+
+        err_msg!("none of the configured storage areas accept new blobs")
+    }
+
+    fn start_resumable_staging(&mut self, key: &DigestData) -> Result<Box<Staging>> {
+        for tier in &mut self.tiers {
+            if tier.accepts_ingest {
+                return tier.storage.start_resumable_staging(key);
+            }
+        }
+
+        err_msg!("none of the configured storage areas accept new blobs")
+    }
+
+    fn gc(&mut self, keep: &HashSet<DigestData>, started: SystemTime, dry_run: bool) -> Result<GcReport> {
+        let mut report = GcReport::default();
+
+        for tier in &mut self.tiers {
+            let sub = tier.storage.gc(keep, started, dry_run)?;
+            report.removed.extend(sub.removed);
+            report.linked.extend(sub.linked);
+            report.stale_staging.extend(sub.stale_staging);
+        }
+
+        Ok(report)
+    }
+
+    fn register_manifest(&mut self, path: &Path) -> Result<()> {
+        for tier in &mut self.tiers {
+            tier.storage.register_manifest(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn registered_manifests(&mut self) -> Result<Vec<PathBuf>> {
+        let mut all = Vec::new();
+
+        for tier in &mut self.tiers {
+            for path in tier.storage.registered_manifests()? {
+                if !all.contains(&path) {
+                    all.push(path);
+                }
+            }
+        }
+
+        Ok(all)
+    }
+
+    fn set_pinned(&mut self, digest: &DigestData, pinned: bool) -> Result<()> {
+        for tier in &mut self.tiers {
+            tier.storage.set_pinned(digest, pinned)?;
+        }
+
+        Ok(())
+    }
+
+    fn enforce_budget(&mut self, keep: &HashSet<DigestData>) -> Result<Vec<StoredBlob>> {
+        let mut evicted = Vec::new();
+
+        for tier in &mut self.tiers {
+            evicted.extend(tier.storage.enforce_budget(keep)?);
+        }
+
+        Ok(evicted)
+    }
+}
@@ -6,6 +6,8 @@ extern crate clap;
 
 use blobman::config::UserConfig;
 use blobman::errors::Result;
+use blobman::manifest::BlobInfo;
+use blobman::storage::{Availability, Storage};
 use blobman::notify::{BufferingNotificationBackend, ChatterLevel};
 use blobman::notify::termcolor::TermcolorNotificationBackend;
 use clap::{Arg, ArgMatches, App, SubCommand};
@@ -13,7 +15,68 @@ use std::io::{self, Write};
 use std::process;
 
 
-fn inner(matches: ArgMatches, config: UserConfig, nbe: &mut TermcolorNotificationBackend) -> Result<i32> {
+fn describe_availability(binfo: &BlobInfo, storage: &Storage) -> Result<String> {
+    Ok(match binfo.availability(storage)? {
+        Availability::File(p) => format!("available at {}", p.display()),
+        Availability::Stream => "available as a stream".to_owned(),
+        Availability::Missing => "missing".to_owned(),
+    })
+}
+
+
+fn apply_network_options(m: &ArgMatches, config: &mut UserConfig) -> Result<()> {
+    if let Some(j) = m.value_of("jobs") {
+        let n: usize = ctry!(j.parse(); "couldn\'t parse \"{}\" as a number of jobs", j);
+        config.set_max_concurrent_downloads(n);
+    }
+
+    if let Some(r) = m.value_of("retries") {
+        let n: u32 = ctry!(r.parse(); "couldn\'t parse \"{}\" as a number of retries", r);
+        config.set_download_retries(n);
+    }
+
+    if let Some(t) = m.value_of("connect_timeout") {
+        let secs: u64 = ctry!(t.parse(); "couldn\'t parse \"{}\" as a number of seconds", t);
+        config.set_connect_timeout(secs);
+    }
+
+    if let Some(t) = m.value_of("read_timeout") {
+        let secs: u64 = ctry!(t.parse(); "couldn\'t parse \"{}\" as a number of seconds", t);
+        config.set_read_timeout(secs);
+    }
+
+    Ok(())
+}
+
+
+fn network_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("jobs")
+            .long("jobs")
+            .short("j")
+            .value_name("N")
+            .help("The maximum number of downloads to run at once"),
+        Arg::with_name("retries")
+            .long("retries")
+            .value_name("N")
+            .help("How many times to retry downloads that fail for temporary reasons"),
+        Arg::with_name("connect_timeout")
+            .long("connect-timeout")
+            .value_name("SECS")
+            .help("Give up on connecting to a server after this long (0 for no limit)"),
+        Arg::with_name("read_timeout")
+            .long("read-timeout")
+            .value_name("SECS")
+            .help("Give up on a server that sends nothing for this long (0 for no limit)"),
+    ]
+}
+
+
+fn inner(matches: ArgMatches, mut config: UserConfig, nbe: &mut TermcolorNotificationBackend) -> Result<i32> {
+    if matches.is_present("offline") {
+        config.set_offline(true);
+    }
+
     if let Some(cat_m) = matches.subcommand_matches("cat") {
         let mut sess = blobman::Session::new(&config, nbe)?;
         let mut bstream = sess.open_blob(cat_m.value_of("NAME").unwrap())?;
@@ -22,12 +85,114 @@ fn inner(matches: ArgMatches, config: UserConfig, nbe: &mut TermcolorNotificatio
         stdout.flush()?; // note: empirically, this is necessary
     } else if let Some(fetch_m) = matches.subcommand_matches("fetch") {
         let mode = fetch_m.value_of("MODE").unwrap().parse()?;
+        let urls: Vec<&str> = fetch_m.values_of("URL").unwrap().collect();
+        apply_network_options(fetch_m, &mut config)?;
         let mut sess = blobman::Session::new(&config, nbe)?;
-        sess.ingest_from_url(mode, fetch_m.value_of("URL").unwrap(), fetch_m.value_of("name"))?;
+
+        let n_bad = if urls.len() == 1 {
+            sess.ingest_from_url(mode, urls[0], fetch_m.value_of("name"))?;
+            0
+        } else if fetch_m.is_present("name") {
+            return err_msg!("\"--name\" can only be used when fetching a single URL");
+        } else {
+            sess.ingest_from_urls(mode, &urls)?
+        };
+
+        // Blobs that were fetched successfully are recorded even if others
+        // failed.
+        sess.rewrite_manifest()?;
+
+        if n_bad > 0 {
+            return Ok(1);
+        }
+    } else if let Some(ingest_m) = matches.subcommand_matches("ingest") {
+        let mode = ingest_m.value_of("MODE").unwrap().parse()?;
+        let path = ingest_m.value_of("FILE").unwrap();
+        let mut sess = blobman::Session::new(&config, nbe)?;
+
+        if path == "-" {
+            let name = match ingest_m.value_of("name") {
+                Some(n) => n,
+                None => { return err_msg!("a name must be specified with \"--name\" when ingesting from standard input"); },
+            };
+            let stdin = io::stdin();
+            let mut lock = stdin.lock();
+            sess.ingest_from_reader(mode, &mut lock, name)?;
+        } else {
+            sess.ingest_from_path(mode, path, ingest_m.value_of("name"))?;
+        }
+
         sess.rewrite_manifest()?;
+    } else if let Some(gc_m) = matches.subcommand_matches("gc") {
+        let mut sess = blobman::Session::new(&config, nbe)?;
+        sess.gc(gc_m.is_present("dry_run"))?;
+    } else if let Some(info_m) = matches.subcommand_matches("info") {
+        let name = info_m.value_of("NAME").unwrap();
+        let mut sess = blobman::Session::new(&config, nbe)?;
+        let storage = ctry!(sess.get_storage(); "cannot open storage backend");
+        let binfo = match sess.manifest().lookup(name) {
+            Some(b) => b,
+            None => { return err_msg!("no known blob named \"{}\"", name); },
+        };
+
+        println!("name:    {}", name);
+        println!("size:    {}", binfo.size());
+        println!("sha256:  {}", binfo.digest().to_string());
+        println!("url:     {}", binfo.url().unwrap_or("(none)"));
+        for mirror in binfo.urls().iter().skip(1) {
+            println!("mirror:  {}", mirror);
+        }
+        println!("storage: {}", describe_availability(binfo, &*storage)?);
+    } else if let Some(_list_m) = matches.subcommand_matches("list") {
+        let mut sess = blobman::Session::new(&config, nbe)?;
+        let storage = ctry!(sess.get_storage(); "cannot open storage backend");
+        let mut blobs: Vec<_> = sess.manifest().iter().collect();
+        blobs.sort_by_key(|&(name, _)| name);
+
+        for (name, binfo) in blobs {
+            let avail = match binfo.availability(&*storage)? {
+                Availability::File(_) => "file",
+                Availability::Stream => "stream",
+                Availability::Missing => "missing",
+            };
+
+            println!("{}\t{}\t{}\t{}\t{}", name, binfo.size(), binfo.digest().to_string(),
+                     avail, binfo.url().unwrap_or("-"));
+        }
+    } else if let Some(mirror_m) = matches.subcommand_matches("mirror") {
+        if let Some(add_m) = mirror_m.subcommand_matches("add") {
+            let mut sess = blobman::Session::new(&config, nbe)?;
+            sess.add_mirror(add_m.value_of("NAME").unwrap(), add_m.value_of("URL").unwrap())?;
+            sess.rewrite_manifest()?;
+        } else {
+            return err_msg!("you must specify a subcommand; try \"blobman help mirror\"");
+        }
+    } else if let Some(pin_m) = matches.subcommand_matches("pin") {
+        let mut sess = blobman::Session::new(&config, nbe)?;
+        sess.pin_blob(pin_m.value_of("NAME").unwrap(), true)?;
     } else if let Some(provide_m) = matches.subcommand_matches("provide") {
         let mut sess = blobman::Session::new(&config, nbe)?;
         sess.provide_blob(provide_m.value_of("NAME").unwrap())?;
+    } else if let Some(sync_m) = matches.subcommand_matches("sync") {
+        apply_network_options(sync_m, &mut config)?;
+        let mut sess = blobman::Session::new(&config, nbe)?;
+
+        if sess.sync()? > 0 {
+            return Ok(1);
+        }
+    } else if let Some(unpin_m) = matches.subcommand_matches("unpin") {
+        let mut sess = blobman::Session::new(&config, nbe)?;
+        sess.pin_blob(unpin_m.value_of("NAME").unwrap(), false)?;
+    } else if let Some(verify_m) = matches.subcommand_matches("verify") {
+        let names: Vec<&str> = match verify_m.values_of("NAME") {
+            Some(v) => v.collect(),
+            None => Vec::new(),
+        };
+        let mut sess = blobman::Session::new(&config, nbe)?;
+
+        if sess.verify_blobs(&names)? > 0 {
+            return Ok(1);
+        }
     } else {
         return err_msg!("you must specify a subcommand; try \"blobman help\"");
     }
@@ -47,6 +212,10 @@ fn main() {
              .help("How much chatter to print when running")
              .possible_values(&["default", "minimal"])
              .default_value("default"))
+        .arg(Arg::with_name("offline")
+             .long("offline")
+             .global(true)
+             .help("Fail instead of accessing the network (also enabled by $BLOBMAN_OFFLINE)"))
         .subcommand(SubCommand::with_name("cat")
                     .about("Stream blob data to standard output")
                     .arg(Arg::with_name("NAME")
@@ -67,8 +236,60 @@ fn main() {
                          .help("How to act if the blob is already registered")
                          .possible_values(blobman::IngestMode::stringifications())
                          .default_value("update"))
+                    .args(&network_args())
                     .arg(Arg::with_name("URL")
-                         .help("The URL to download")
+                         .help("The URL(s) to download")
+                         .required(true)
+                         .multiple(true)
+                         .index(1)))
+        .subcommand(SubCommand::with_name("ingest")
+                    .about("Ingest a local file")
+                    .arg(Arg::with_name("name")
+                         .long("name")
+                         .short("n")
+                         .value_name("NAME")
+                         .help("The name to use for the ingested blob [default: derived from FILE]"))
+                    .arg(Arg::with_name("MODE")
+                         .long("mode")
+                         .short("m")
+                         .value_name("MODE")
+                         .help("How to act if the blob is already registered")
+                         .possible_values(blobman::IngestMode::stringifications())
+                         .default_value("update"))
+                    .arg(Arg::with_name("FILE")
+                         .help("The file to ingest, or \"-\" for standard input")
+                         .required(true)
+                         .index(1)))
+        .subcommand(SubCommand::with_name("gc")
+                    .about("Delete stored blobs that are no longer referenced")
+                    .arg(Arg::with_name("dry_run")
+                         .long("dry-run")
+                         .short("n")
+                         .help("Report what would be deleted without deleting anything")))
+        .subcommand(SubCommand::with_name("info")
+                    .about("Show information about a blob")
+                    .arg(Arg::with_name("NAME")
+                         .help("The name of the blob to describe")
+                         .required(true)
+                         .index(1)))
+        .subcommand(SubCommand::with_name("list")
+                    .about("List the known blobs and whether they are available locally"))
+        .subcommand(SubCommand::with_name("mirror")
+                    .about("Manage the alternative URLs from which blobs can be obtained")
+                    .subcommand(SubCommand::with_name("add")
+                                .about("Add a URL to try if a blob's main URL fails")
+                                .arg(Arg::with_name("NAME")
+                                     .help("The name of the blob")
+                                     .required(true)
+                                     .index(1))
+                                .arg(Arg::with_name("URL")
+                                     .help("The URL of the mirror")
+                                     .required(true)
+                                     .index(2))))
+        .subcommand(SubCommand::with_name("pin")
+                    .about("Prevent a blob from being evicted from storage")
+                    .arg(Arg::with_name("NAME")
+                         .help("The name of the blob to pin")
                          .required(true)
                          .index(1)))
         .subcommand(SubCommand::with_name("provide")
@@ -77,6 +298,21 @@ fn main() {
                          .help("The name of the blob to provide")
                          .required(true)
                          .index(1)))
+        .subcommand(SubCommand::with_name("sync")
+                    .about("Download every blob in the manifest that is missing from storage")
+                    .args(&network_args()))
+        .subcommand(SubCommand::with_name("unpin")
+                    .about("Allow a pinned blob to be evicted from storage again")
+                    .arg(Arg::with_name("NAME")
+                         .help("The name of the blob to unpin")
+                         .required(true)
+                         .index(1)))
+        .subcommand(SubCommand::with_name("verify")
+                    .about("Check that stored blobs match the manifest")
+                    .arg(Arg::with_name("NAME")
+                         .help("The names of the blobs to check [default: all of them]")
+                         .multiple(true)
+                         .index(1)))
         .get_matches();
 
     let chatter = match matches.value_of("chatter_level").unwrap() {